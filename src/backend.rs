@@ -0,0 +1,132 @@
+//! Sources of device permissions and fds.
+//!
+//! [`TermuxUsb`] is the real thing. [`FakeBackend`] opens device nodes (or
+//! any regular files standing in for them) directly and runs the callback
+//! in-process, so the whole handoff can be exercised on a plain Linux box.
+
+use log::debug;
+use nix::{errno::Errno, fcntl::OFlag, sys::stat::Mode};
+use std::{
+    io,
+    os::{
+        fd::{AsRawFd, FromRawFd, OwnedFd},
+        unix::net::UnixDatagram,
+    },
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use crate::{
+    handoff::{self, HandoffError, ReceivedUsbFd},
+    termux,
+};
+
+/// Lists devices and obtains fds for them.
+pub trait UsbPermissionBackend {
+    /// Device paths that can be requested.
+    fn list_devices(&self) -> anyhow::Result<Vec<String>>;
+
+    /// Obtains an fd for `dev`. The fd is sent over `sock_send` and
+    /// received from its peer `sock_recv`, just like from a `termux-usb -e`
+    /// callback.
+    fn request_fd(
+        &self,
+        dev: &str,
+        sock_send: &UnixDatagram,
+        sock_recv: &UnixDatagram,
+    ) -> Result<ReceivedUsbFd, HandoffError>;
+}
+
+/// Devices granted by the `termux-usb` command of Termux:API.
+#[derive(Debug, Clone)]
+pub struct TermuxUsb {
+    /// Executable run as the `termux-usb -e` callback.
+    pub self_path: PathBuf,
+    /// How long to wait for the user to answer the permission dialog.
+    pub timeout: Option<Duration>,
+}
+
+impl TermuxUsb {
+    pub fn new(self_path: &Path, timeout: Option<Duration>) -> Self {
+        TermuxUsb {
+            self_path: self_path.to_path_buf(),
+            timeout,
+        }
+    }
+}
+
+impl UsbPermissionBackend for TermuxUsb {
+    fn list_devices(&self) -> anyhow::Result<Vec<String>> {
+        Ok(termux::get_termux_usb_list()?)
+    }
+
+    fn request_fd(
+        &self,
+        dev: &str,
+        sock_send: &UnixDatagram,
+        sock_recv: &UnixDatagram,
+    ) -> Result<ReceivedUsbFd, HandoffError> {
+        termux::request_usb_fd(dev, &self.self_path, sock_send, sock_recv, self.timeout)
+    }
+}
+
+/// Grants every listed device without asking by opening it directly.
+///
+/// Devices can be real `/dev/bus/usb` nodes (when permissions allow) or
+/// regular files. Missing files are reported as gone, unreadable ones as
+/// denied.
+#[derive(Debug, Clone, Default)]
+pub struct FakeBackend {
+    pub devices: Vec<PathBuf>,
+}
+
+impl FakeBackend {
+    pub fn new<P: AsRef<Path>>(devices: &[P]) -> Self {
+        FakeBackend {
+            devices: devices.iter().map(|d| d.as_ref().to_path_buf()).collect(),
+        }
+    }
+
+    fn open(dev: &str) -> Result<OwnedFd, HandoffError> {
+        let open = |flags| nix::fcntl::open(dev, flags | OFlag::O_CLOEXEC, Mode::empty());
+        let fd = match open(OFlag::O_RDWR) {
+            Err(Errno::EACCES) | Err(Errno::EROFS) | Err(Errno::EISDIR) => open(OFlag::O_RDONLY),
+            res => res,
+        };
+        match fd {
+            Ok(fd) => Ok(unsafe { OwnedFd::from_raw_fd(fd) }),
+            Err(Errno::ENOENT) | Err(Errno::ENODEV) | Err(Errno::ENXIO) => {
+                Err(HandoffError::DeviceGone {
+                    dev: dev.to_string(),
+                })
+            }
+            Err(Errno::EACCES) | Err(Errno::EPERM) => Err(HandoffError::PermissionDenied {
+                dev: dev.to_string(),
+            }),
+            Err(e) => Err(io::Error::from(e).into()),
+        }
+    }
+}
+
+impl UsbPermissionBackend for FakeBackend {
+    fn list_devices(&self) -> anyhow::Result<Vec<String>> {
+        Ok(self
+            .devices
+            .iter()
+            .map(|d| d.display().to_string())
+            .collect())
+    }
+
+    fn request_fd(
+        &self,
+        dev: &str,
+        sock_send: &UnixDatagram,
+        sock_recv: &UnixDatagram,
+    ) -> Result<ReceivedUsbFd, HandoffError> {
+        // like termux-usb, the fd only lives as long as the callback
+        let usb_fd = Self::open(dev)?;
+        debug!("fake backend opened {}", dev);
+        handoff::hand_over_usb_fd(sock_send, dev, &usb_fd.as_raw_fd().to_string())?;
+        handoff::recv_usb_fd(sock_recv)
+    }
+}
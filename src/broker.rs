@@ -0,0 +1,434 @@
+//! Long-running broker handing out cached USB fds to local clients.
+//!
+//! Every device is requested from `termux-usb` at most once. Clients connect
+//! to the broker socket, name a device by path, `VID:PID` or serial number
+//! (or its [`DeviceIdentity`] if it has none) and get a duplicate of the
+//! cached fd back, so tools like adb and fastboot don't each trigger a
+//! permission dialog. Only clients running as the broker's own uid are
+//! served.
+
+use anyhow::{bail, Context};
+use log::{info, warn};
+use nix::{
+    sys::socket::{getsockopt, sockopt::PeerCredentials},
+    unistd::{getuid, Uid},
+};
+use sendfd::{RecvWithFd, SendWithFd};
+use std::{
+    fmt,
+    io::{self, Read, Write},
+    os::{
+        fd::{AsRawFd, FromRawFd, OwnedFd, RawFd},
+        unix::net::{SocketAddr, UnixDatagram, UnixListener, UnixStream},
+    },
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::Duration,
+};
+
+#[cfg(target_os = "android")]
+use std::os::android::net::SocketAddrExt;
+#[cfg(target_os = "linux")]
+use std::os::linux::net::SocketAddrExt;
+
+use crate::{
+    backend::UsbPermissionBackend,
+    filter::DeviceFilter,
+    handoff::HandoffError,
+    identity::DeviceIdentity,
+    info::init_device_info,
+    protocol::{Message, MessageType, ERROR_NO_DEVICE, ERROR_OTHER, MAX_MESSAGE_LEN},
+    strings::DEFAULT_LANGUAGES,
+    sysfs::Sysfs,
+    usbfs,
+};
+
+/// Socket name used when none is given; a leading `@` means abstract.
+pub const DEFAULT_BROKER_SOCKET: &str = "@termux-usb-broker";
+
+/// How a client names the device it wants.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DeviceSelector {
+    /// Device node, e.g. `/dev/bus/usb/001/002`.
+    Path(String),
+    /// Hexadecimal vendor and product id, e.g. `18d1:4ee7`.
+    VidPid(u16, u16),
+    /// Serial number string.
+    Serial(String),
+}
+
+impl FromStr for DeviceSelector {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        if s.is_empty() {
+            bail!("empty device selector");
+        }
+        if s.starts_with('/') {
+            return Ok(DeviceSelector::Path(s.to_string()));
+        }
+        if let Some((vid, pid)) = s.split_once(':') {
+            if let (Ok(vid), Ok(pid)) = (u16::from_str_radix(vid, 16), u16::from_str_radix(pid, 16))
+            {
+                return Ok(DeviceSelector::VidPid(vid, pid));
+            }
+        }
+        Ok(DeviceSelector::Serial(s.to_string()))
+    }
+}
+
+impl DeviceSelector {
+    /// The sysfs criteria a device must meet to be a candidate.
+    pub fn filter(&self) -> DeviceFilter {
+        match self {
+            DeviceSelector::Path(path) => DeviceFilter {
+                path: Some(path.clone()),
+                ..DeviceFilter::default()
+            },
+            DeviceSelector::VidPid(vid, pid) => DeviceFilter {
+                vendor_id: Some(*vid),
+                product_id: Some(*pid),
+                ..DeviceFilter::default()
+            },
+            DeviceSelector::Serial(serial) => DeviceFilter {
+                serial: Some(serial.clone()),
+                ..DeviceFilter::default()
+            },
+        }
+    }
+}
+
+impl fmt::Display for DeviceSelector {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DeviceSelector::Path(path) => f.write_str(path),
+            DeviceSelector::VidPid(vid, pid) => write!(f, "{:04x}:{:04x}", vid, pid),
+            DeviceSelector::Serial(serial) => f.write_str(serial),
+        }
+    }
+}
+
+/// Device fd obtained from `termux-usb` and kept open by the broker.
+#[derive(Debug)]
+pub struct BrokeredDevice {
+    pub dev_path: String,
+    pub vendor_id: Option<u16>,
+    pub product_id: Option<u16>,
+    pub serial_number: Option<String>,
+    pub identity: Option<DeviceIdentity>,
+    fd: OwnedFd,
+}
+
+impl BrokeredDevice {
+    pub fn matches(&self, selector: &DeviceSelector) -> bool {
+        match selector {
+            DeviceSelector::Path(path) => &self.dev_path == path,
+            DeviceSelector::VidPid(vid, pid) => {
+                self.vendor_id == Some(*vid) && self.product_id == Some(*pid)
+            }
+            DeviceSelector::Serial(serial) => {
+                self.serial_number.as_ref() == Some(serial)
+                    || self.identity.as_ref().is_some_and(|id| id.matches(serial))
+            }
+        }
+    }
+
+    pub fn fd(&self) -> RawFd {
+        self.fd.as_raw_fd()
+    }
+
+    /// Whether the device was unplugged, which usbfs reports as ENODEV.
+    /// Fds that are not usbfs nodes, e.g. fake devices, never count as gone.
+    pub fn is_gone(&self) -> bool {
+        matches!(
+            usbfs::connect_info(self.fd()),
+            Err(e) if e.raw_os_error() == Some(libc::ENODEV)
+        )
+    }
+}
+
+#[cfg(feature = "libusb")]
+impl Drop for BrokeredDevice {
+    /// Lets go of the libusb handle before the fd is closed.
+    fn drop(&mut self) {
+        if let Ok(shared) = crate::context::SharedContext::global() {
+            shared.release(self.fd());
+        }
+    }
+}
+
+/// How long a client may take to send its request.
+const CLIENT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Cache of device fds acquired through a [`UsbPermissionBackend`].
+pub struct Broker {
+    backend: Box<dyn UsbPermissionBackend>,
+    sysfs: Sysfs,
+    devices: Vec<BrokeredDevice>,
+}
+
+impl Broker {
+    /// Creates an empty broker requesting devices from `backend`, usually
+    /// [`TermuxUsb`](crate::backend::TermuxUsb), after checking them against
+    /// `sysfs`.
+    pub fn new(backend: Box<dyn UsbPermissionBackend>, sysfs: Sysfs) -> Self {
+        Broker {
+            backend,
+            sysfs,
+            devices: vec![],
+        }
+    }
+
+    pub fn devices(&self) -> &[BrokeredDevice] {
+        &self.devices
+    }
+
+    /// Requests `dev` from the backend unless it is already cached.
+    pub fn acquire(&mut self, dev: &str) -> anyhow::Result<&BrokeredDevice> {
+        self.forget_gone();
+        if let Some(i) = self.devices.iter().position(|d| d.dev_path == dev) {
+            return Ok(&self.devices[i]);
+        }
+
+        let (sock_send, sock_recv) =
+            UnixDatagram::pair().context("could not create socket pair")?;
+        let msg = self.backend.request_fd(dev, &sock_send, &sock_recv)?;
+        let usb_fd = msg
+            .fd
+            .with_context(|| format!("no fd was handed over for {}", dev))?;
+        let fd = unsafe { OwnedFd::from_raw_fd(usb_fd) };
+
+        let mut device = BrokeredDevice {
+            dev_path: dev.to_string(),
+            vendor_id: None,
+            product_id: None,
+            serial_number: None,
+            identity: None,
+            fd,
+        };
+        match init_device_info(device.fd(), DEFAULT_LANGUAGES) {
+            Ok(usb_info) => {
+                device.vendor_id = Some(usb_info.vendor_id);
+                device.product_id = Some(usb_info.product_id);
+                device.serial_number = usb_info.serial_number;
+                device.identity = Some(usb_info.identity);
+            }
+            Err(e) => warn!("could not read device info of {}: {:#}", dev, e),
+        }
+        info!("acquired {} as fd {}", dev, device.fd());
+
+        self.devices.push(device);
+        Ok(self.devices.last().unwrap())
+    }
+
+    /// Finds a cached device matching `selector`, otherwise requests the
+    /// listed devices whose sysfs metadata matches until one does, so
+    /// others don't trigger a permission dialog.
+    pub fn find(&mut self, selector: &DeviceSelector) -> anyhow::Result<&BrokeredDevice> {
+        self.forget_gone();
+        if let Some(i) = self.devices.iter().position(|d| d.matches(selector)) {
+            return Ok(&self.devices[i]);
+        }
+
+        let filter = selector.filter();
+        let candidates: Vec<_> = self
+            .backend
+            .list_devices()?
+            .iter()
+            .filter(|dev| !self.devices.iter().any(|d| &d.dev_path == *dev))
+            .map(|dev| self.sysfs.device(dev))
+            .filter(|dev| filter.matches(dev))
+            .map(|dev| dev.dev_path)
+            .collect();
+        if let DeviceSelector::Path(path) = selector {
+            if candidates.is_empty() {
+                bail!("no device matching {}: not listed by termux-usb", path);
+            }
+            return self.acquire(path);
+        }
+        for dev in candidates {
+            let matched = match self.acquire(&dev) {
+                Ok(device) => device.matches(selector),
+                Err(e) => {
+                    warn!("{:#}", e);
+                    false
+                }
+            };
+            if matched {
+                return Ok(self.devices.last().unwrap());
+            }
+        }
+        bail!("no device matching {}", selector)
+    }
+
+    /// Drops a cached device and closes its fd.
+    pub fn forget(&mut self, dev: &str) {
+        self.devices.retain(|d| d.dev_path != dev);
+    }
+
+    /// Forgets unplugged devices so they are requested again when they
+    /// come back.
+    fn forget_gone(&mut self) {
+        let gone: Vec<_> = self
+            .devices
+            .iter()
+            .filter(|d| d.is_gone())
+            .map(|d| d.dev_path.clone())
+            .collect();
+        for dev in gone {
+            info!("{} was unplugged, forgetting it", dev);
+            self.forget(&dev);
+        }
+    }
+
+    /// Serves client requests on `listener` until an I/O error occurs.
+    pub fn serve(&mut self, listener: &UnixListener) -> anyhow::Result<()> {
+        loop {
+            let (stream, _) = listener.accept().context("error accepting client")?;
+            if let Err(e) = self.handle_client(stream) {
+                warn!("client request failed: {:#}", e);
+            }
+        }
+    }
+
+    fn handle_client(&mut self, mut stream: UnixStream) -> anyhow::Result<()> {
+        // the socket is reachable by every app, the fds must not be
+        if let Err(e) = check_peer(&stream, getuid()) {
+            send_error(&mut stream, ERROR_OTHER, &format!("{:#}", e))?;
+            return Err(e);
+        }
+        // clients are served one at a time, so a silent one must not stall
+        // the others
+        stream
+            .set_read_timeout(Some(CLIENT_TIMEOUT))
+            .context("could not set client timeout")?;
+        let mut request = vec![];
+        (&mut stream)
+            .take(MAX_MESSAGE_LEN as u64 + 1)
+            .read_to_end(&mut request)
+            .context("error reading request")?;
+
+        let selector = Message::decode(&request)
+            .map_err(anyhow::Error::from)
+            .and_then(|msg| match (msg.kind, msg.selector) {
+                (MessageType::Request, Some(selector)) => Ok(selector),
+                (kind, _) => bail!("expected a request with a selector, got {:?}", kind),
+            });
+        let selector = match selector {
+            Ok(selector) => selector,
+            Err(e) => {
+                send_error(&mut stream, ERROR_OTHER, &format!("bad request: {:#}", e))?;
+                return Err(e);
+            }
+        };
+        info!("client requested {}", selector);
+
+        let found = selector
+            .parse::<DeviceSelector>()
+            .and_then(|selector| self.find(&selector));
+        match found {
+            Ok(device) => {
+                let mut msg = Message::device_fd(Path::new(&device.dev_path), 1);
+                msg.serial = device
+                    .identity
+                    .as_ref()
+                    .map(|id| id.id.clone())
+                    .or_else(|| device.serial_number.clone());
+                msg.vendor_id = device.vendor_id;
+                msg.product_id = device.product_id;
+                stream
+                    .send_with_fd(&msg.encode()?, &[device.fd()])
+                    .context("error sending fd to client")?;
+            }
+            Err(e) => {
+                let code = e
+                    .downcast_ref::<HandoffError>()
+                    .map_or(ERROR_NO_DEVICE, HandoffError::wire_code);
+                send_error(&mut stream, code, &format!("{:#}", e))?;
+                return Err(e);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Fails unless the process on the other end of `stream` runs as `uid`.
+fn check_peer(stream: &UnixStream, uid: Uid) -> anyhow::Result<()> {
+    let creds = getsockopt(stream.as_raw_fd(), PeerCredentials)
+        .context("could not get client credentials")?;
+    if creds.uid() != uid.as_raw() {
+        bail!(
+            "client uid {} (pid {}) is not allowed, only uid {}",
+            creds.uid(),
+            creds.pid(),
+            uid
+        );
+    }
+    Ok(())
+}
+
+fn send_error(stream: &mut UnixStream, code: u32, message: &str) -> anyhow::Result<()> {
+    stream
+        .write_all(&Message::error(None, code, message).encode()?)
+        .context("error sending reply to client")
+}
+
+fn socket_addr(name: &str) -> io::Result<SocketAddr> {
+    match name.strip_prefix('@') {
+        Some(abstract_name) => SocketAddr::from_abstract_name(abstract_name),
+        None => SocketAddr::from_pathname(name),
+    }
+}
+
+/// Binds the broker socket. Names starting with `@` are abstract, anything
+/// else is a filesystem path (a stale socket file is replaced).
+pub fn bind_broker_socket(name: &str) -> io::Result<UnixListener> {
+    if !name.starts_with('@') && Path::new(name).exists() {
+        std::fs::remove_file(name)?;
+    }
+    UnixListener::bind_addr(&socket_addr(name)?)
+}
+
+/// Asks the broker listening on `name` for the device matching `selector`.
+///
+/// Returns the device path and a new fd referring to the device.
+pub fn request_from_broker(name: &str, selector: &str) -> anyhow::Result<(PathBuf, OwnedFd)> {
+    let mut stream = UnixStream::connect_addr(&socket_addr(name)?)
+        .with_context(|| format!("could not connect to broker at {}", name))?;
+    stream
+        .write_all(&Message::request(selector).encode()?)
+        .context("error sending request")?;
+    stream
+        .shutdown(std::net::Shutdown::Write)
+        .context("error sending request")?;
+
+    let mut buf = vec![0; MAX_MESSAGE_LEN];
+    let mut fds = vec![0; 1];
+    let (size, fd_count) = stream
+        .recv_with_fd(&mut buf, &mut fds)
+        .context("error receiving reply from broker")?;
+    let fd = (fd_count > 0).then(|| unsafe { OwnedFd::from_raw_fd(fds[0]) });
+
+    let msg = Message::decode(&buf[..size]).context("invalid reply from broker")?;
+    match (msg.kind, fd) {
+        (MessageType::DeviceFd, Some(fd)) => Ok((msg.dev_path.unwrap_or_default(), fd)),
+        (MessageType::Error, _) => {
+            Err(anyhow::Error::new(HandoffError::from_message(&msg)).context("broker refused"))
+        }
+        (kind, _) => bail!("unexpected {:?} reply from broker without fd", kind),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rejects_clients_of_other_users() {
+        let (server, _client) = UnixStream::pair().unwrap();
+        assert!(check_peer(&server, getuid()).is_ok());
+        let other = Uid::from_raw(getuid().as_raw().wrapping_add(1));
+        let err = check_peer(&server, other).unwrap_err();
+        assert!(err.to_string().contains("is not allowed"), "{:#}", err);
+    }
+}
@@ -0,0 +1,349 @@
+use clap::{Args, Parser, Subcommand};
+use std::{path::PathBuf, time::Duration};
+use termux_usb::{
+    broker::DEFAULT_BROKER_SOCKET, sysfs::DEFAULT_SYSFS_ROOT, termux::DEFAULT_REQUEST_TIMEOUT,
+    usbcat::DEFAULT_CHUNK_SIZE, CatOptions, ClassFilter, DeviceFilter, MatchMode,
+};
+
+/// Inspect USB devices and broker their fds through termux-usb.
+///
+/// When started by `termux-usb -e` without a subcommand, the granted device
+/// is reported (or handed to the parent if TERMUX_ADB_SOCK_FD is set).
+#[derive(Debug, Parser)]
+#[command(version)]
+pub struct Cli {
+    /// Print machine-readable JSON instead of text
+    #[arg(long, global = true)]
+    pub json: bool,
+
+    /// Seconds to wait for termux-usb to hand over a device, 0 waits forever
+    #[arg(long, global = true, value_name = "SECONDS",
+          default_value_t = DEFAULT_REQUEST_TIMEOUT.as_secs())]
+    pub timeout: u64,
+
+    /// Open this file or device node directly instead of asking termux-usb
+    /// (for testing; may be repeated)
+    #[arg(long, global = true, value_name = "PATH")]
+    pub fake_device: Vec<PathBuf>,
+
+    /// Where sysfs is mounted (for testing against a fake tree)
+    #[arg(long, global = true, value_name = "DIR", default_value = DEFAULT_SYSFS_ROOT)]
+    pub sysfs_root: PathBuf,
+
+    /// Preferred LANGIDs for string descriptors (hex, comma separated)
+    #[arg(long, global = true, value_name = "LANGID", value_delimiter = ',',
+          value_parser = parse_hex_id, default_value = "0409")]
+    pub language: Vec<u16>,
+
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+impl Cli {
+    pub fn request_timeout(&self) -> Option<Duration> {
+        (self.timeout > 0).then(|| Duration::from_secs(self.timeout))
+    }
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// List devices reported by `termux-usb -l` with metadata from sysfs
+    List(FilterArgs),
+    /// Show the device descriptor
+    Info(DeviceArgs),
+    /// Show the serial number and check it against its sysfs attribute
+    Serial(DeviceArgs),
+    /// Show all configuration, interface and endpoint descriptors
+    Descriptors(DescriptorArgs),
+    /// Dump the string descriptors referenced by the other descriptors in
+    /// every supported language
+    Strings(DeviceArgs),
+    /// Show how the device node and its sysfs entry were found
+    Resolve(DeviceArgs),
+    /// Issue a control request, e.g. a vendor request
+    Control(ControlArgs),
+    /// Copy stdin to a bulk OUT endpoint and a bulk IN endpoint to stdout
+    Usbcat(UsbcatArgs),
+    /// Print what an interrupt IN endpoint reports until interrupted
+    ///
+    /// Each report is printed as a timestamped hexdump, or with --json as
+    /// one JSON object per line.
+    Monitor(MonitorArgs),
+    /// Request every listed device through termux-usb and report it
+    Broker(FilterArgs),
+    /// Keep device fds open and hand them out to clients over a socket
+    Serve(ServeArgs),
+    /// Send TERMUX_USB_FD to the parent over TERMUX_ADB_SOCK_FD
+    ///
+    /// This is what `broker` runs under `termux-usb -e`.
+    ChildSend,
+}
+
+#[derive(Debug, Args)]
+pub struct DeviceArgs {
+    /// Device to request through termux-usb, e.g. /dev/bus/usb/001/002
+    ///
+    /// Defaults to the first device matching the filter options or, without
+    /// those, to the fd in TERMUX_USB_FD when run by `termux-usb -e`.
+    /// With --broker, VID:PID and serial numbers are accepted as well.
+    pub device: Option<String>,
+
+    #[command(flatten)]
+    pub filter: FilterArgs,
+
+    /// Get the fd from a running `serve` broker instead of termux-usb
+    #[arg(long)]
+    pub broker: bool,
+
+    /// Broker socket to connect to; names starting with @ are abstract
+    #[arg(long, default_value = DEFAULT_BROKER_SOCKET, requires = "broker")]
+    pub socket: String,
+}
+
+#[derive(Debug, Args)]
+pub struct DescriptorArgs {
+    #[command(flatten)]
+    pub device: DeviceArgs,
+
+    /// Decode the raw descriptors read from the fd instead of using libusb
+    /// (done anyway if libusb fails)
+    #[arg(long)]
+    pub native: bool,
+
+    /// Read the sysfs `descriptors` attribute of the device; no permission
+    /// is requested
+    #[arg(long, conflicts_with = "native")]
+    pub from_sysfs: bool,
+}
+
+#[derive(Debug, Args)]
+pub struct ControlArgs {
+    #[command(flatten)]
+    pub device: DeviceArgs,
+
+    /// bmRequestType; bit 7 set (e.g. 0xc0) reads from the device
+    #[arg(long, value_name = "TYPE", value_parser = parse_int::<u8>)]
+    pub request_type: u8,
+
+    /// bRequest
+    #[arg(long, value_parser = parse_int::<u8>)]
+    pub request: u8,
+
+    /// wValue
+    #[arg(long, value_parser = parse_int::<u16>, default_value = "0")]
+    pub value: u16,
+
+    /// wIndex
+    #[arg(long, value_parser = parse_int::<u16>, default_value = "0")]
+    pub index: u16,
+
+    /// Bytes to read for a device-to-host request
+    #[arg(long, value_parser = parse_int::<u16>, conflicts_with_all = ["data", "data_file"])]
+    pub length: Option<u16>,
+
+    /// Payload of a host-to-device request in hex, e.g. "01 02 ff"
+    #[arg(long, value_name = "HEX", conflicts_with = "data_file")]
+    pub data: Option<String>,
+
+    /// Read the payload of a host-to-device request from a file (- for stdin)
+    #[arg(long, value_name = "PATH")]
+    pub data_file: Option<PathBuf>,
+
+    /// Milliseconds to wait for the transfer, 0 waits forever
+    #[arg(long, value_name = "MS", default_value_t = 1000)]
+    pub transfer_timeout: u64,
+
+    /// Write the bytes read to stdout as they are instead of a hexdump
+    #[arg(long, conflicts_with = "json")]
+    pub raw: bool,
+}
+
+impl ControlArgs {
+    pub fn transfer_timeout(&self) -> Duration {
+        Duration::from_millis(self.transfer_timeout)
+    }
+}
+
+#[derive(Debug, Args)]
+pub struct UsbcatArgs {
+    #[command(flatten)]
+    pub device: DeviceArgs,
+
+    /// Interface to claim
+    #[arg(long, value_parser = parse_int::<u8>, default_value = "0")]
+    pub interface: u8,
+
+    /// Detach a kernel driver bound to the interface (reattached on exit)
+    #[arg(long)]
+    pub detach: bool,
+
+    /// OUT endpoint address; defaults to the interface's first bulk OUT
+    #[arg(long, value_name = "ADDR", value_parser = parse_int::<u8>)]
+    pub out_endpoint: Option<u8>,
+
+    /// IN endpoint address; defaults to the interface's first bulk IN
+    #[arg(long, value_name = "ADDR", value_parser = parse_int::<u8>)]
+    pub in_endpoint: Option<u8>,
+
+    /// Only copy stdin to the device
+    #[arg(long, conflicts_with_all = ["receive_only", "in_endpoint"])]
+    pub send_only: bool,
+
+    /// Only copy the device to stdout; stdin is not read
+    #[arg(long, conflicts_with = "out_endpoint")]
+    pub receive_only: bool,
+
+    /// Largest transfer in bytes
+    #[arg(long, value_name = "BYTES", default_value_t = DEFAULT_CHUNK_SIZE)]
+    pub chunk_size: usize,
+
+    /// Milliseconds to wait for each transfer, 0 waits forever
+    #[arg(long, value_name = "MS", default_value_t = 1000)]
+    pub transfer_timeout: u64,
+
+    /// Send a zero-length packet if stdin ends on a packet boundary
+    #[arg(long)]
+    pub zlp: bool,
+
+    /// Stop reading when the device sends a zero-length packet
+    #[arg(long)]
+    pub stop_on_zlp: bool,
+
+    /// Keep reading after stdin ends (or with --receive-only, from the
+    /// start) instead of stopping at the first timeout
+    #[arg(long)]
+    pub keep_reading: bool,
+}
+
+impl UsbcatArgs {
+    pub fn options(&self) -> CatOptions {
+        CatOptions {
+            chunk_size: self.chunk_size,
+            timeout: Duration::from_millis(self.transfer_timeout),
+            send_zlp: self.zlp,
+            stop_on_zlp: self.stop_on_zlp,
+            stop_on_idle: !self.keep_reading,
+        }
+    }
+}
+
+#[derive(Debug, Args)]
+pub struct MonitorArgs {
+    #[command(flatten)]
+    pub device: DeviceArgs,
+
+    /// Interface to claim; defaults to the one with the endpoint
+    #[arg(long, value_parser = parse_int::<u8>)]
+    pub interface: Option<u8>,
+
+    /// Interrupt IN endpoint address; defaults to the first one of the
+    /// interface or device
+    #[arg(long, value_name = "ADDR", value_parser = parse_int::<u8>)]
+    pub endpoint: Option<u8>,
+
+    /// Leave kernel drivers bound instead of detaching them
+    #[arg(long)]
+    pub no_detach: bool,
+
+    /// Bytes requested per transfer; defaults to wMaxPacketSize
+    #[arg(long, value_name = "BYTES")]
+    pub buffer_size: Option<usize>,
+
+    /// Milliseconds each transfer waits before it is resubmitted
+    #[arg(long, value_name = "MS", default_value_t = 500)]
+    pub transfer_timeout: u64,
+
+    /// Stop after this many reports
+    #[arg(long, value_name = "N")]
+    pub count: Option<u64>,
+}
+
+impl MonitorArgs {
+    pub fn transfer_timeout(&self) -> Duration {
+        Duration::from_millis(self.transfer_timeout)
+    }
+}
+
+#[derive(Debug, Args)]
+pub struct ServeArgs {
+    /// Socket to listen on; names starting with @ are abstract
+    #[arg(long, default_value = DEFAULT_BROKER_SOCKET)]
+    pub socket: String,
+
+    /// Request every device listed by termux-usb up front
+    #[arg(long, conflicts_with = "devices")]
+    pub all: bool,
+
+    /// Devices to request up front; others are requested on demand
+    pub devices: Vec<String>,
+
+    /// Request the devices matching these options up front
+    #[command(flatten)]
+    pub filter: FilterArgs,
+}
+
+/// Criteria checked against sysfs before any permission is requested.
+#[derive(Debug, Args)]
+pub struct FilterArgs {
+    /// Only devices with this vendor id (hex)
+    #[arg(long, value_name = "VID", value_parser = parse_hex_id)]
+    pub vid: Option<u16>,
+
+    /// Only devices with this product id (hex)
+    #[arg(long, value_name = "PID", value_parser = parse_hex_id)]
+    pub pid: Option<u16>,
+
+    /// Only the device with this serial number (or VID:PID@port identity)
+    #[arg(long, value_name = "SERIAL")]
+    pub serial: Option<String>,
+
+    /// Only devices with this device or interface class, e.g. ff:42:01
+    #[arg(long, value_name = "CLASS[:SUB[:PROTO]]")]
+    pub class: Option<ClassFilter>,
+
+    /// Only the device with this node, e.g. /dev/bus/usb/001/002
+    #[arg(long, value_name = "PATH")]
+    pub path: Option<String>,
+
+    /// Stop at the first matching device instead of taking all of them
+    #[arg(long)]
+    pub first: bool,
+}
+
+impl FilterArgs {
+    pub fn filter(&self) -> DeviceFilter {
+        DeviceFilter {
+            vendor_id: self.vid,
+            product_id: self.pid,
+            serial: self.serial.clone(),
+            class: self.class,
+            path: self.path.clone(),
+        }
+    }
+
+    pub fn mode(&self) -> MatchMode {
+        if self.first {
+            MatchMode::First
+        } else {
+            MatchMode::All
+        }
+    }
+}
+
+/// Parses a number given in decimal or, with a 0x prefix, in hex.
+fn parse_int<T: TryFrom<u64>>(s: &str) -> Result<T, String> {
+    let n = match s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+        Some(digits) => u64::from_str_radix(digits, 16),
+        None => s.parse(),
+    }
+    .map_err(|e| format!("invalid number {:?}: {}", s, e))?;
+    T::try_from(n).map_err(|_| format!("{} is out of range", s))
+}
+
+fn parse_hex_id(s: &str) -> Result<u16, String> {
+    let digits = s
+        .strip_prefix("0x")
+        .or_else(|| s.strip_prefix("0X"))
+        .unwrap_or(s);
+    u16::from_str_radix(digits, 16).map_err(|e| format!("invalid hex id {:?}: {}", s, e))
+}
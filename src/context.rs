@@ -0,0 +1,169 @@
+//! One libusb context shared by every fd the process wraps.
+//!
+//! `LIBUSB_OPTION_NO_DEVICE_DISCOVERY` is process-wide and has to be set
+//! before a context is created, so it is set exactly once here. Handles are
+//! cached per fd and a single thread handles libusb events for all of them.
+
+use anyhow::Context as _;
+use log::{debug, warn};
+use nix::{
+    sys::stat::fstat,
+    unistd::{lseek, Whence},
+};
+use rusb::{constants::LIBUSB_OPTION_NO_DEVICE_DISCOVERY, DeviceHandle, UsbContext};
+use std::{
+    os::fd::RawFd,
+    ptr::null_mut,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex, MutexGuard, Once,
+    },
+    thread::{self, JoinHandle},
+    time::Duration,
+};
+
+/// A libusb handle owned by a [`SharedContext`].
+pub type SharedHandle = Arc<DeviceHandle<rusb::Context>>;
+
+/// How long the event thread blocks before checking whether to stop.
+const EVENT_POLL: Duration = Duration::from_millis(200);
+
+static DISABLE_DISCOVERY: Once = Once::new();
+static GLOBAL: Mutex<Option<Arc<SharedContext>>> = Mutex::new(None);
+
+/// Stops libusb from scanning `/dev/bus/usb`, which Android does not allow.
+pub fn disable_device_discovery() {
+    DISABLE_DISCOVERY.call_once(|| {
+        debug!("calling libusb_set_option");
+        unsafe { rusb::ffi::libusb_set_option(null_mut(), LIBUSB_OPTION_NO_DEVICE_DISCOVERY) };
+    });
+}
+
+struct Wrapped {
+    fd: RawFd,
+    /// Device number of the node at wrap time, to notice reused fd numbers.
+    rdev: u64,
+    handle: SharedHandle,
+}
+
+/// A libusb context with an event thread and a handle per wrapped fd.
+pub struct SharedContext {
+    context: rusb::Context,
+    handles: Mutex<Vec<Wrapped>>,
+    running: Arc<AtomicBool>,
+    events: Option<JoinHandle<()>>,
+}
+
+impl SharedContext {
+    /// Creates a context and starts its event thread.
+    pub fn new() -> anyhow::Result<Self> {
+        disable_device_discovery();
+        let context = rusb::Context::new().context("libusb_init error")?;
+        let running = Arc::new(AtomicBool::new(true));
+        let events = {
+            let (context, running) = (context.clone(), running.clone());
+            thread::Builder::new()
+                .name("libusb-events".to_string())
+                .spawn(move || {
+                    while running.load(Ordering::Relaxed) {
+                        if let Err(e) = context.handle_events(Some(EVENT_POLL)) {
+                            warn!("libusb event handling failed: {}", e);
+                            thread::sleep(EVENT_POLL);
+                        }
+                    }
+                })
+                .context("could not start libusb event thread")?
+        };
+        Ok(SharedContext {
+            context,
+            handles: Mutex::new(vec![]),
+            running,
+            events: Some(events),
+        })
+    }
+
+    /// The context used by the whole process, created on first use.
+    pub fn global() -> anyhow::Result<Arc<SharedContext>> {
+        let mut global = GLOBAL.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(shared) = &*global {
+            return Ok(shared.clone());
+        }
+        let shared = Arc::new(SharedContext::new()?);
+        *global = Some(shared.clone());
+        Ok(shared)
+    }
+
+    pub fn context(&self) -> &rusb::Context {
+        &self.context
+    }
+
+    fn handles(&self) -> MutexGuard<'_, Vec<Wrapped>> {
+        self.handles.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Returns the handle for `usb_fd`, wrapping the fd on first use.
+    pub fn wrap(&self, usb_fd: RawFd) -> anyhow::Result<SharedHandle> {
+        let mut handles = self.handles();
+        prune(&mut handles);
+        if let Some(w) = handles.iter().find(|w| w.fd == usb_fd) {
+            return Ok(w.handle.clone());
+        }
+
+        let rdev = fstat(usb_fd)
+            .with_context(|| format!("error checking fd: {}", usb_fd))?
+            .st_rdev;
+        lseek(usb_fd, 0, Whence::SeekSet)
+            .with_context(|| format!("error seeking fd: {}", usb_fd))?;
+        debug!("opening device from {}", usb_fd);
+        let handle =
+            unsafe { self.context.open_device_with_fd(usb_fd) }.context("error opening device")?;
+        let handle = Arc::new(handle);
+        handles.push(Wrapped {
+            fd: usb_fd,
+            rdev,
+            handle: handle.clone(),
+        });
+        Ok(handle)
+    }
+
+    /// Drops the cached handle of `usb_fd`; call this before closing the fd.
+    /// The handle is closed once its last clone is gone.
+    pub fn release(&self, usb_fd: RawFd) -> bool {
+        let mut handles = self.handles();
+        let before = handles.len();
+        handles.retain(|w| w.fd != usb_fd);
+        before != handles.len()
+    }
+
+    /// Drops handles whose fd was closed or now refers to another node.
+    pub fn prune(&self) -> usize {
+        prune(&mut self.handles())
+    }
+
+    /// Fds with a cached handle.
+    pub fn fds(&self) -> Vec<RawFd> {
+        self.handles().iter().map(|w| w.fd).collect()
+    }
+}
+
+fn prune(handles: &mut Vec<Wrapped>) -> usize {
+    let before = handles.len();
+    handles.retain(|w| match fstat(w.fd) {
+        Ok(st) if st.st_rdev == w.rdev => true,
+        _ => {
+            debug!("fd {} was closed, dropping its handle", w.fd);
+            false
+        }
+    });
+    before - handles.len()
+}
+
+impl Drop for SharedContext {
+    fn drop(&mut self) {
+        self.handles().clear();
+        self.running.store(false, Ordering::Relaxed);
+        if let Some(events) = self.events.take() {
+            let _ = events.join();
+        }
+    }
+}
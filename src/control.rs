@@ -0,0 +1,178 @@
+//! Arbitrary control transfers, e.g. vendor requests.
+
+use anyhow::{bail, Context};
+use serde::Serialize;
+use std::{fmt, time::Duration};
+
+use crate::{handle::UsbDeviceHandle, hex};
+
+/// Direction bit of `bmRequestType`; set for device-to-host requests.
+pub const REQUEST_TYPE_IN: u8 = 0x80;
+
+/// What the data stage of a control transfer carries.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ControlData {
+    /// Read up to this many bytes from the device.
+    In(u16),
+    /// Send these bytes to the device.
+    Out(Vec<u8>),
+}
+
+/// The setup packet of a control transfer plus its data stage.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ControlRequest {
+    pub request_type: u8,
+    pub request: u8,
+    pub value: u16,
+    pub index: u16,
+    pub data: ControlData,
+}
+
+/// Outcome of a control transfer.
+#[derive(Debug, Clone, Serialize)]
+pub struct ControlResponse {
+    pub request_type: u8,
+    pub request: u8,
+    pub value: u16,
+    pub index: u16,
+    /// `wLength` as sent in the setup packet.
+    pub length: u16,
+    /// Bytes actually read or written.
+    pub transferred: usize,
+    /// Bytes read from the device; empty for OUT requests.
+    #[serde(serialize_with = "hex::serialize")]
+    pub data: Vec<u8>,
+}
+
+impl ControlRequest {
+    /// Checks that the direction bit of `request_type` agrees with `data`.
+    pub fn new(
+        request_type: u8,
+        request: u8,
+        value: u16,
+        index: u16,
+        data: ControlData,
+    ) -> anyhow::Result<Self> {
+        let device_to_host = request_type & REQUEST_TYPE_IN != 0;
+        match &data {
+            ControlData::In(_) if !device_to_host => bail!(
+                "bmRequestType {:#04x} is host-to-device, nothing can be read",
+                request_type
+            ),
+            ControlData::Out(payload) if device_to_host => bail!(
+                "bmRequestType {:#04x} is device-to-host, {}",
+                request_type,
+                if payload.is_empty() {
+                    "a length to read is needed"
+                } else {
+                    "no data can be sent"
+                }
+            ),
+            ControlData::Out(payload) if payload.len() > usize::from(u16::MAX) => {
+                bail!("{} bytes do not fit in wLength", payload.len())
+            }
+            _ => {}
+        }
+        Ok(ControlRequest {
+            request_type,
+            request,
+            value,
+            index,
+            data,
+        })
+    }
+
+    /// `wLength` of the setup packet.
+    pub fn length(&self) -> u16 {
+        match &self.data {
+            ControlData::In(length) => *length,
+            ControlData::Out(payload) => payload.len() as u16,
+        }
+    }
+}
+
+/// Issues `request` on `handle` and returns what was transferred.
+pub fn control_transfer<H: UsbDeviceHandle + ?Sized>(
+    handle: &H,
+    request: &ControlRequest,
+    timeout: Duration,
+) -> anyhow::Result<ControlResponse> {
+    let mut data = vec![];
+    let transferred = match &request.data {
+        ControlData::In(length) => {
+            data.resize(usize::from(*length), 0);
+            let n = handle.read_control(
+                request.request_type,
+                request.request,
+                request.value,
+                request.index,
+                &mut data,
+                timeout,
+            );
+            data.truncate(*n.as_ref().unwrap_or(&0));
+            n
+        }
+        ControlData::Out(payload) => handle.write_control(
+            request.request_type,
+            request.request,
+            request.value,
+            request.index,
+            payload,
+            timeout,
+        ),
+    }
+    .with_context(|| format!("control transfer failed ({})", request))?;
+    Ok(ControlResponse {
+        request_type: request.request_type,
+        request: request.request,
+        value: request.value,
+        index: request.index,
+        length: request.length(),
+        transferred,
+        data,
+    })
+}
+
+impl fmt::Display for ControlRequest {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "bmRequestType {:#04x} bRequest {:#04x} wValue {:#06x} wIndex {:#06x} wLength {}",
+            self.request_type,
+            self.request,
+            self.value,
+            self.index,
+            self.length()
+        )
+    }
+}
+
+impl fmt::Display for ControlResponse {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.request_type & REQUEST_TYPE_IN == 0 {
+            return writeln!(f, "wrote {} of {} bytes", self.transferred, self.length);
+        }
+        writeln!(f, "read {} of {} bytes", self.transferred, self.length)?;
+        f.write_str(&hex::hexdump(&self.data))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn checks_direction_against_data() {
+        assert!(ControlRequest::new(0xc0, 0x01, 0, 0, ControlData::In(4)).is_ok());
+        assert!(ControlRequest::new(0x40, 0x01, 0, 0, ControlData::Out(vec![1])).is_ok());
+        assert!(ControlRequest::new(0x40, 0x01, 0, 0, ControlData::In(4)).is_err());
+        assert!(ControlRequest::new(0xc0, 0x01, 0, 0, ControlData::Out(vec![])).is_err());
+
+        let request = ControlRequest::new(0x41, 0x02, 1, 2, ControlData::Out(vec![0; 3])).unwrap();
+        assert_eq!(request.length(), 3);
+        assert_eq!(
+            request.to_string(),
+            "bmRequestType 0x41 bRequest 0x02 wValue 0x0001 wIndex 0x0002 wLength 3"
+        );
+    }
+}
@@ -0,0 +1,266 @@
+//! Configuration, interface and endpoint descriptors of a device.
+//!
+//! This is a replacement for `lsusb -v`, which cannot enumerate devices on
+//! unrooted Android.
+
+use serde::Serialize;
+use std::fmt;
+
+use crate::info::UsbDeviceInfo;
+#[cfg(feature = "libusb")]
+use {
+    crate::{
+        device::open_device_with_fd,
+        info::read_device_info,
+        strings::{StringReader, DEFAULT_LANGUAGES},
+    },
+    anyhow::Context,
+    libc::c_int,
+    rusb::{DeviceHandle, UsbContext},
+};
+
+/// Device descriptor together with all of its configurations.
+#[derive(Debug, Clone, Serialize)]
+pub struct UsbDescriptorTree {
+    pub device: UsbDeviceInfo,
+    pub configurations: Vec<UsbConfiguration>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct UsbConfiguration {
+    /// `bConfigurationValue`
+    pub number: u8,
+    pub max_power_ma: u16,
+    pub self_powered: bool,
+    pub remote_wakeup: bool,
+    pub description: Option<String>,
+    pub interfaces: Vec<UsbInterface>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct UsbInterface {
+    pub number: u8,
+    pub alt_settings: Vec<UsbAltSetting>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct UsbAltSetting {
+    pub interface_number: u8,
+    pub setting_number: u8,
+    pub class_code: u8,
+    pub sub_class_code: u8,
+    pub protocol_code: u8,
+    pub description: Option<String>,
+    pub endpoints: Vec<UsbEndpoint>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct UsbEndpoint {
+    /// `bEndpointAddress` including the direction bit.
+    pub address: u8,
+    pub direction: EndpointDirection,
+    pub transfer_type: TransferType,
+    pub max_packet_size: u16,
+    pub interval: u8,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum EndpointDirection {
+    /// Device to host.
+    In,
+    /// Host to device.
+    Out,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TransferType {
+    Control,
+    Isochronous,
+    Bulk,
+    Interrupt,
+}
+
+impl UsbEndpoint {
+    /// Endpoint number without the direction bit.
+    pub fn number(&self) -> u8 {
+        self.address & 0x0f
+    }
+}
+
+#[cfg(feature = "libusb")]
+impl From<rusb::Direction> for EndpointDirection {
+    fn from(direction: rusb::Direction) -> Self {
+        match direction {
+            rusb::Direction::In => EndpointDirection::In,
+            rusb::Direction::Out => EndpointDirection::Out,
+        }
+    }
+}
+
+#[cfg(feature = "libusb")]
+impl From<rusb::TransferType> for TransferType {
+    fn from(transfer_type: rusb::TransferType) -> Self {
+        match transfer_type {
+            rusb::TransferType::Control => TransferType::Control,
+            rusb::TransferType::Isochronous => TransferType::Isochronous,
+            rusb::TransferType::Bulk => TransferType::Bulk,
+            rusb::TransferType::Interrupt => TransferType::Interrupt,
+        }
+    }
+}
+
+/// Opens the device behind `usb_fd` and reads its [`UsbDescriptorTree`].
+#[cfg(feature = "libusb")]
+pub fn init_libusb_descriptor_tree(usb_fd: c_int) -> anyhow::Result<UsbDescriptorTree> {
+    let usb_handle = open_device_with_fd(usb_fd)?;
+    read_descriptor_tree(&usb_handle, DEFAULT_LANGUAGES)
+}
+
+/// Walks all configurations of the device behind `usb_handle`, reading
+/// strings in the first supported of `languages`.
+#[cfg(feature = "libusb")]
+pub fn read_descriptor_tree<T: UsbContext>(
+    usb_handle: &DeviceHandle<T>,
+    languages: &[u16],
+) -> anyhow::Result<UsbDescriptorTree> {
+    let device = read_device_info(usb_handle, languages)?;
+    let usb_dev = usb_handle.device();
+    let strings = StringReader::new(usb_handle, languages);
+
+    let mut configurations = Vec::with_capacity(device.num_configurations.into());
+    for i in 0..device.num_configurations {
+        let config = usb_dev
+            .config_descriptor(i)
+            .with_context(|| format!("error getting config descriptor {}", i))?;
+
+        let interfaces = config
+            .interfaces()
+            .map(|iface| UsbInterface {
+                number: iface.number(),
+                alt_settings: iface
+                    .descriptors()
+                    .map(|alt| UsbAltSetting {
+                        interface_number: alt.interface_number(),
+                        setting_number: alt.setting_number(),
+                        class_code: alt.class_code(),
+                        sub_class_code: alt.sub_class_code(),
+                        protocol_code: alt.protocol_code(),
+                        description: strings.read(alt.description_string_index(), "interface"),
+                        endpoints: alt
+                            .endpoint_descriptors()
+                            .map(|ep| UsbEndpoint {
+                                address: ep.address(),
+                                direction: ep.direction().into(),
+                                transfer_type: ep.transfer_type().into(),
+                                max_packet_size: ep.max_packet_size(),
+                                interval: ep.interval(),
+                            })
+                            .collect(),
+                    })
+                    .collect(),
+            })
+            .collect();
+
+        configurations.push(UsbConfiguration {
+            number: config.number(),
+            max_power_ma: config.max_power(),
+            self_powered: config.self_powered(),
+            remote_wakeup: config.remote_wakeup(),
+            description: strings.read(config.description_string_index(), "configuration"),
+            interfaces,
+        });
+    }
+
+    Ok(UsbDescriptorTree {
+        device,
+        configurations,
+    })
+}
+
+impl fmt::Display for EndpointDirection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EndpointDirection::In => f.write_str("IN"),
+            EndpointDirection::Out => f.write_str("OUT"),
+        }
+    }
+}
+
+impl fmt::Display for TransferType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TransferType::Control => f.write_str("Control"),
+            TransferType::Isochronous => f.write_str("Isochronous"),
+            TransferType::Bulk => f.write_str("Bulk"),
+            TransferType::Interrupt => f.write_str("Interrupt"),
+        }
+    }
+}
+
+impl fmt::Display for UsbDescriptorTree {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let opt = |s: &Option<String>| s.clone().unwrap_or_default();
+        let d = &self.device;
+        writeln!(
+            f,
+            "ID {:04x}:{:04x} {} {}",
+            d.vendor_id,
+            d.product_id,
+            opt(&d.manufacturer),
+            opt(&d.product)
+        )?;
+        write!(f, "{}", d)?;
+        for config in &self.configurations {
+            let mut attributes = 0x80;
+            if config.self_powered {
+                attributes |= 0x40;
+            }
+            if config.remote_wakeup {
+                attributes |= 0x20;
+            }
+            writeln!(f, "  Configuration Descriptor:")?;
+            writeln!(f, "    bNumInterfaces     {:>5}", config.interfaces.len())?;
+            writeln!(f, "    bConfigurationValue {:>4}", config.number)?;
+            writeln!(f, "    iConfiguration      {}", opt(&config.description))?;
+            writeln!(f, "    bmAttributes         0x{:02x}", attributes)?;
+            if config.self_powered {
+                writeln!(f, "      Self Powered")?;
+            }
+            if config.remote_wakeup {
+                writeln!(f, "      Remote Wakeup")?;
+            }
+            writeln!(f, "    MaxPower          {:>5}mA", config.max_power_ma)?;
+            for alt in config.interfaces.iter().flat_map(|i| &i.alt_settings) {
+                writeln!(f, "    Interface Descriptor:")?;
+                writeln!(f, "      bInterfaceNumber   {:>5}", alt.interface_number)?;
+                writeln!(f, "      bAlternateSetting  {:>5}", alt.setting_number)?;
+                writeln!(f, "      bNumEndpoints      {:>5}", alt.endpoints.len())?;
+                writeln!(f, "      bInterfaceClass    {:>5}", alt.class_code)?;
+                writeln!(f, "      bInterfaceSubClass {:>5}", alt.sub_class_code)?;
+                writeln!(f, "      bInterfaceProtocol {:>5}", alt.protocol_code)?;
+                writeln!(f, "      iInterface          {}", opt(&alt.description))?;
+                for ep in &alt.endpoints {
+                    writeln!(f, "      Endpoint Descriptor:")?;
+                    writeln!(
+                        f,
+                        "        bEndpointAddress     0x{:02x}  EP {} {}",
+                        ep.address,
+                        ep.number(),
+                        ep.direction
+                    )?;
+                    writeln!(f, "        Transfer Type        {}", ep.transfer_type)?;
+                    writeln!(
+                        f,
+                        "        wMaxPacketSize  0x{:04x}  {} bytes",
+                        ep.max_packet_size,
+                        ep.max_packet_size & 0x7ff
+                    )?;
+                    writeln!(f, "        bInterval          {:>5}", ep.interval)?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
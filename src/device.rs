@@ -0,0 +1,529 @@
+//! Opening USB devices from file descriptors obtained through termux-usb.
+
+use anyhow::{bail, Context};
+use libc::c_int;
+use log::{debug, info, warn};
+use serde::Serialize;
+use std::{
+    env, fmt, fs, io,
+    os::fd::RawFd,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+#[cfg(feature = "libusb")]
+use crate::{
+    context::{SharedContext, SharedHandle},
+    info::read_device_info,
+};
+use crate::{
+    descriptors::{EndpointDirection, TransferType},
+    handle::UsbDeviceHandle,
+    identity::DeviceIdentity,
+    info::{init_usbfs_device_info, UsbDeviceInfo},
+    rawdesc::{read_descriptors_from_fd, EndpointDescriptor, InterfaceDescriptor, RawDescriptors},
+    resolve::{resolve_node, ResolvedNode},
+    strings::DEFAULT_LANGUAGES,
+    sysfs::Sysfs,
+    termux::{usb_fd_from_env, TERMUX_USB_DEV},
+};
+
+/// Serial number of a device together with the sysfs attribute holding it.
+#[derive(Debug, Clone, Serialize)]
+pub struct UsbSerial {
+    /// Serial number read from the device's string descriptor, if it has one.
+    pub number: Option<String>,
+    /// Serial number or, without one, the `VID:PID@port` fallback.
+    pub identity: DeviceIdentity,
+    /// Path of the `serial` attribute under `/sys/bus/usb/devices`.
+    pub path: PathBuf,
+    /// Whether that attribute agrees with `number`.
+    pub sysfs: SerialCheck,
+}
+
+/// Outcome of comparing the descriptor serial with the sysfs attribute,
+/// which is what adb on Android goes by.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "status", rename_all = "snake_case")]
+pub enum SerialCheck {
+    /// Both agree, or neither has a serial.
+    Match,
+    /// sysfs reports a different serial (`None` if it has none).
+    Mismatch { sysfs: Option<String> },
+    /// The attribute exists but reading it is not permitted.
+    Denied { reason: String },
+    /// The attribute could not be read for another reason.
+    Unreadable { reason: String },
+}
+
+/// Compares the serial attribute at `path` with the descriptor serial.
+pub fn check_sysfs_serial(path: &Path, number: Option<&str>) -> SerialCheck {
+    let sysfs = match fs::read_to_string(path) {
+        Ok(s) => Some(s.trim_end_matches('\n').to_string()),
+        // devices without iSerialNumber have no attribute at all
+        Err(e) if e.kind() == io::ErrorKind::NotFound => None,
+        Err(e) if e.kind() == io::ErrorKind::PermissionDenied => {
+            return SerialCheck::Denied {
+                reason: e.to_string(),
+            }
+        }
+        Err(e) => {
+            return SerialCheck::Unreadable {
+                reason: e.to_string(),
+            }
+        }
+    };
+    if sysfs.as_deref() == number {
+        SerialCheck::Match
+    } else {
+        warn!(
+            "serial mismatch: descriptor {:?}, {} {:?}",
+            number,
+            path.display(),
+            sysfs
+        );
+        SerialCheck::Mismatch { sysfs }
+    }
+}
+
+/// Opens a libusb handle for an already opened usbfs file descriptor.
+///
+/// The handle lives on the process-wide [`SharedContext`], so opening the
+/// same fd again returns the same handle.
+#[cfg(feature = "libusb")]
+pub fn open_device_with_fd(usb_fd: c_int) -> anyhow::Result<SharedHandle> {
+    SharedContext::global()?.wrap(usb_fd)
+}
+
+/// Reads the serial number of the device behind `usb_fd` and locates its
+/// sysfs `serial` attribute.
+///
+/// Devices without a serial string (`iSerialNumber` 0) get a fallback
+/// identity instead of an error.
+#[cfg(feature = "libusb")]
+pub fn init_libusb_device_serial(usb_fd: c_int) -> anyhow::Result<UsbSerial> {
+    let usb_handle = open_device_with_fd(usb_fd)?;
+    let usb_info = read_device_info(&usb_handle, DEFAULT_LANGUAGES)?;
+    device_serial(usb_fd, &resolve_fd(usb_fd), usb_info)
+}
+
+/// Reads the serial of the device behind `usb_fd` with libusb if built with
+/// it, with usbfs otherwise, preferring the given LANGIDs.
+pub fn init_device_serial(usb_fd: c_int, languages: &[u16]) -> anyhow::Result<UsbSerial> {
+    #[cfg(feature = "libusb")]
+    return device_serial(
+        usb_fd,
+        &resolve_fd(usb_fd),
+        read_device_info(&*open_device_with_fd(usb_fd)?, languages)?,
+    );
+    #[cfg(not(feature = "libusb"))]
+    return init_usbfs_device_serial(usb_fd, languages);
+}
+
+/// Like [`init_libusb_device_serial`] but reads the descriptors through
+/// usbfs, reading strings in the first supported of `languages`.
+pub fn init_usbfs_device_serial(usb_fd: c_int, languages: &[u16]) -> anyhow::Result<UsbSerial> {
+    let node = resolve_fd(usb_fd);
+    device_serial(
+        usb_fd,
+        &node,
+        init_usbfs_device_info(usb_fd, &node, languages)?,
+    )
+}
+
+/// Resolves a bare fd; no device path is known, so `TERMUX_USB_DEV` is not
+/// used (it may belong to another device).
+fn resolve_fd(usb_fd: c_int) -> ResolvedNode {
+    resolve_node(usb_fd, None, &Sysfs::default())
+}
+
+fn device_serial(
+    usb_fd: c_int,
+    node: &ResolvedNode,
+    usb_info: UsbDeviceInfo,
+) -> anyhow::Result<UsbSerial> {
+    debug!(
+        "device descriptor: vid={:04x}, pid={:04x}, serial={:?}",
+        usb_info.vendor_id, usb_info.product_id, usb_info.serial_number
+    );
+
+    let path = sysfs_serial_path(usb_fd, node)?;
+    let mut identity = usb_info.identity;
+    if identity.port_path.is_none() {
+        // libusb may not know the ports of a wrapped fd, sysfs does
+        let port_path = path
+            .parent()
+            .and_then(|dir| dir.file_name())
+            .map(|name| name.to_string_lossy().into_owned());
+        identity = DeviceIdentity::new(
+            identity.serial_number,
+            identity.vendor_id,
+            identity.product_id,
+            port_path,
+        );
+    }
+
+    let sysfs = check_sysfs_serial(&path, usb_info.serial_number.as_deref());
+    Ok(UsbSerial {
+        number: usb_info.serial_number,
+        identity,
+        path,
+        sysfs,
+    })
+}
+
+/// Locates the sysfs `serial` attribute of the device behind `usb_fd` in
+/// `node`, as found by [`resolve_node`].
+pub fn sysfs_serial_path(usb_fd: c_int, node: &ResolvedNode) -> anyhow::Result<PathBuf> {
+    let Some(dir) = &node.sysfs_path else {
+        let reasons: Vec<_> = node
+            .attempts
+            .iter()
+            .map(|a| format!("{}: {}", a.strategy, a.detail))
+            .collect();
+        bail!(
+            "error: could not find the sysfs directory of fd {} ({})",
+            usb_fd,
+            reasons.join("; ")
+        );
+    };
+    let dev_serial_path = dir.join("serial");
+    info!(
+        "device serial path: {} (found by {})",
+        dev_serial_path.display(),
+        node.sysfs_strategy()
+            .map_or("?".to_string(), |s| s.to_string())
+    );
+    Ok(dev_serial_path)
+}
+
+/// An open device that stays usable after its descriptors were read.
+///
+/// Owns the handle (and with libusb its context), caches the descriptors and
+/// identity, and releases claimed interfaces when dropped. The fd itself is
+/// left open; it belongs to whoever received it.
+pub struct TermuxUsbDevice {
+    fd: RawFd,
+    dev_path: Option<String>,
+    node: ResolvedNode,
+    handle: Box<dyn UsbDeviceHandle>,
+    info: UsbDeviceInfo,
+    descriptors: RawDescriptors,
+    claimed: Vec<u8>,
+    detached: Vec<u8>,
+}
+
+impl TermuxUsbDevice {
+    /// Opens the device behind `usb_fd`, reading strings in the first
+    /// supported of `languages`. `dev_path` is the usbfs path, if known.
+    pub fn open(
+        usb_fd: RawFd,
+        dev_path: Option<String>,
+        languages: &[u16],
+    ) -> anyhow::Result<Self> {
+        let node = resolve_node(usb_fd, dev_path.as_deref(), &Sysfs::default());
+        #[cfg(feature = "libusb")]
+        let (handle, info) = {
+            let handle = open_device_with_fd(usb_fd)?;
+            let info = read_device_info(&handle, languages)?;
+            (Box::new(handle) as Box<dyn UsbDeviceHandle>, info)
+        };
+        #[cfg(not(feature = "libusb"))]
+        let (handle, info) = (
+            crate::handle::open_usbfs_handle(usb_fd),
+            init_usbfs_device_info(usb_fd, &node, languages)?,
+        );
+        let descriptors = read_descriptors_from_fd(usb_fd)?;
+        debug!("opened {} from fd {}", info.identity, usb_fd);
+        Ok(TermuxUsbDevice {
+            fd: usb_fd,
+            dev_path,
+            node,
+            handle,
+            info,
+            descriptors,
+            claimed: vec![],
+            detached: vec![],
+        })
+    }
+
+    /// Opens the device `termux-usb` passed in `TERMUX_USB_FD`.
+    pub fn from_env() -> anyhow::Result<Self> {
+        let usb_fd = usb_fd_from_env()?;
+        Self::open(usb_fd, env::var(TERMUX_USB_DEV).ok(), DEFAULT_LANGUAGES)
+    }
+
+    pub fn fd(&self) -> RawFd {
+        self.fd
+    }
+
+    pub fn dev_path(&self) -> Option<&str> {
+        self.dev_path.as_deref()
+    }
+
+    /// How the node and sysfs directory of the device were found.
+    pub fn node(&self) -> &ResolvedNode {
+        &self.node
+    }
+
+    pub fn info(&self) -> &UsbDeviceInfo {
+        &self.info
+    }
+
+    pub fn identity(&self) -> &DeviceIdentity {
+        &self.info.identity
+    }
+
+    pub fn descriptors(&self) -> &RawDescriptors {
+        &self.descriptors
+    }
+
+    /// Locates the sysfs `serial` attribute and compares it with the cached
+    /// serial number.
+    pub fn serial(&self) -> anyhow::Result<UsbSerial> {
+        device_serial(self.fd, &self.node, self.info.clone())
+    }
+
+    /// Alternate setting 0 of interface `number` in any configuration.
+    pub fn interface(&self, number: u8) -> Option<&InterfaceDescriptor> {
+        self.interfaces().find(|i| i.interface_number == number)
+    }
+
+    /// Alternate setting 0 of every interface, in descriptor order.
+    pub fn interfaces(&self) -> impl Iterator<Item = &InterfaceDescriptor> {
+        self.descriptors
+            .configurations
+            .iter()
+            .flat_map(|c| &c.interfaces)
+            .filter(|i| i.setting_number == 0)
+    }
+
+    /// The endpoint with `address` in any interface.
+    pub fn endpoint(&self, address: u8) -> Option<&EndpointDescriptor> {
+        self.descriptors
+            .configurations
+            .iter()
+            .flat_map(|c| &c.interfaces)
+            .flat_map(|i| &i.endpoints)
+            .find(|ep| ep.address == address)
+    }
+
+    /// First endpoint of `interface` with the given direction and type.
+    pub fn find_endpoint(
+        &self,
+        interface: u8,
+        direction: EndpointDirection,
+        transfer_type: TransferType,
+    ) -> Option<&EndpointDescriptor> {
+        self.interface(interface)?
+            .endpoints
+            .iter()
+            .find(|ep| ep.direction == direction && ep.transfer_type == transfer_type)
+    }
+
+    /// Claims `interface`, first detaching a kernel driver bound to it if
+    /// `detach` is set and permitted. Detached drivers are reattached on drop.
+    pub fn claim(&mut self, interface: u8, detach: bool) -> anyhow::Result<()> {
+        if detach && self.kernel_driver_active(interface).unwrap_or(false) {
+            // Android may refuse; claiming can still work if nothing is bound
+            match self.detach_kernel_driver(interface) {
+                Ok(()) => {
+                    debug!("detached kernel driver of interface {}", interface);
+                    self.detached.push(interface);
+                }
+                Err(e) => warn!(
+                    "could not detach kernel driver of interface {}: {}",
+                    interface, e
+                ),
+            }
+        }
+        self.claim_interface(interface).with_context(|| {
+            format!(
+                "could not claim interface {}{}",
+                interface,
+                if detach {
+                    ""
+                } else {
+                    " (is a kernel driver bound?)"
+                }
+            )
+        })
+    }
+
+    /// Interfaces claimed through this device and not yet released.
+    pub fn claimed_interfaces(&self) -> &[u8] {
+        &self.claimed
+    }
+}
+
+impl UsbDeviceHandle for TermuxUsbDevice {
+    fn read_control(
+        &self,
+        request_type: u8,
+        request: u8,
+        value: u16,
+        index: u16,
+        buf: &mut [u8],
+        timeout: Duration,
+    ) -> io::Result<usize> {
+        self.handle
+            .read_control(request_type, request, value, index, buf, timeout)
+    }
+
+    fn write_control(
+        &self,
+        request_type: u8,
+        request: u8,
+        value: u16,
+        index: u16,
+        buf: &[u8],
+        timeout: Duration,
+    ) -> io::Result<usize> {
+        self.handle
+            .write_control(request_type, request, value, index, buf, timeout)
+    }
+
+    fn read_bulk(&self, endpoint: u8, buf: &mut [u8], timeout: Duration) -> io::Result<usize> {
+        self.handle.read_bulk(endpoint, buf, timeout)
+    }
+
+    fn write_bulk(&self, endpoint: u8, buf: &[u8], timeout: Duration) -> io::Result<usize> {
+        self.handle.write_bulk(endpoint, buf, timeout)
+    }
+
+    fn read_interrupt(&self, endpoint: u8, buf: &mut [u8], timeout: Duration) -> io::Result<usize> {
+        self.handle.read_interrupt(endpoint, buf, timeout)
+    }
+
+    fn write_interrupt(&self, endpoint: u8, buf: &[u8], timeout: Duration) -> io::Result<usize> {
+        self.handle.write_interrupt(endpoint, buf, timeout)
+    }
+
+    fn claim_interface(&mut self, interface: u8) -> io::Result<()> {
+        self.handle.claim_interface(interface)?;
+        if !self.claimed.contains(&interface) {
+            self.claimed.push(interface);
+        }
+        Ok(())
+    }
+
+    fn release_interface(&mut self, interface: u8) -> io::Result<()> {
+        self.handle.release_interface(interface)?;
+        self.claimed.retain(|&i| i != interface);
+        Ok(())
+    }
+
+    fn kernel_driver_active(&self, interface: u8) -> io::Result<bool> {
+        self.handle.kernel_driver_active(interface)
+    }
+
+    fn detach_kernel_driver(&mut self, interface: u8) -> io::Result<()> {
+        self.handle.detach_kernel_driver(interface)
+    }
+
+    fn attach_kernel_driver(&mut self, interface: u8) -> io::Result<()> {
+        self.handle.attach_kernel_driver(interface)
+    }
+
+    fn clear_halt(&mut self, endpoint: u8) -> io::Result<()> {
+        self.handle.clear_halt(endpoint)
+    }
+
+    fn reset(&mut self) -> io::Result<()> {
+        self.handle.reset()
+    }
+}
+
+impl Drop for TermuxUsbDevice {
+    fn drop(&mut self) {
+        for interface in std::mem::take(&mut self.claimed) {
+            if let Err(e) = self.handle.release_interface(interface) {
+                debug!("could not release interface {}: {}", interface, e);
+            }
+        }
+        for interface in std::mem::take(&mut self.detached) {
+            if let Err(e) = self.handle.attach_kernel_driver(interface) {
+                warn!(
+                    "could not reattach kernel driver of interface {}: {}",
+                    interface, e
+                );
+            }
+        }
+    }
+}
+
+impl fmt::Debug for TermuxUsbDevice {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TermuxUsbDevice")
+            .field("fd", &self.fd)
+            .field("dev_path", &self.dev_path)
+            .field("identity", &self.info.identity)
+            .field("claimed", &self.claimed)
+            .field("detached", &self.detached)
+            .finish()
+    }
+}
+
+/// Extracts the major number from a `dev_t`.
+pub const fn major(dev: u64) -> u64 {
+    ((dev >> 32) & 0xffff_f000) | ((dev >> 8) & 0x0000_0fff)
+}
+
+/// Extracts the minor number from a `dev_t`.
+pub const fn minor(dev: u64) -> u64 {
+    ((dev >> 12) & 0xffff_ff00) | ((dev) & 0x0000_00ff)
+}
+
+impl fmt::Display for UsbSerial {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "{}", self.identity)?;
+        writeln!(f, "{}", self.path.display())?;
+        writeln!(f, "{}", self.sysfs)
+    }
+}
+
+impl fmt::Display for SerialCheck {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SerialCheck::Match => write!(f, "matches sysfs"),
+            SerialCheck::Mismatch { sysfs: Some(s) } => write!(f, "MISMATCH, sysfs has {}", s),
+            SerialCheck::Mismatch { sysfs: None } => write!(f, "MISMATCH, sysfs has no serial"),
+            SerialCheck::Denied { reason } => write!(f, "sysfs serial not readable: {}", reason),
+            SerialCheck::Unreadable { reason } => write!(f, "sysfs serial unreadable: {}", reason),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::process;
+
+    #[test]
+    fn checks_sysfs_serial() {
+        let path = std::env::temp_dir().join(format!("termux-usb-serial-{}", process::id()));
+        fs::write(&path, "ABC123\n").unwrap();
+
+        assert_eq!(
+            check_sysfs_serial(&path, Some("ABC123")),
+            SerialCheck::Match
+        );
+        assert_eq!(
+            check_sysfs_serial(&path, Some("XYZ")),
+            SerialCheck::Mismatch {
+                sysfs: Some("ABC123".to_string())
+            }
+        );
+
+        fs::remove_file(&path).unwrap();
+        assert_eq!(check_sysfs_serial(&path, None), SerialCheck::Match);
+        assert_eq!(
+            check_sysfs_serial(&path, Some("ABC123")),
+            SerialCheck::Mismatch { sysfs: None }
+        );
+        assert!(matches!(
+            check_sysfs_serial(Path::new("/"), None),
+            SerialCheck::Unreadable { .. }
+        ));
+    }
+}
@@ -0,0 +1,239 @@
+//! Picking devices by their sysfs metadata.
+//!
+//! Every `termux-usb -e` call pops up a permission dialog, so devices are
+//! matched against what sysfs tells about them first and only the matching
+//! ones are requested. A device whose metadata cannot be read does not
+//! match a criterion that needs it.
+
+use anyhow::{bail, Context};
+use std::{fmt, str::FromStr};
+
+use crate::sysfs::SysfsDevice;
+
+/// Class triple like `ff:42:01`; subclass and protocol may be left out.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ClassFilter {
+    pub class: u8,
+    pub sub_class: Option<u8>,
+    pub protocol: Option<u8>,
+}
+
+impl ClassFilter {
+    fn matches_triple(&self, class: u8, sub_class: u8, protocol: u8) -> bool {
+        self.class == class
+            && self.sub_class.is_none_or(|s| s == sub_class)
+            && self.protocol.is_none_or(|p| p == protocol)
+    }
+
+    /// True if the device or one of its interfaces has this class.
+    pub fn matches(&self, device: &SysfsDevice) -> bool {
+        let device_class = match (
+            device.device_class,
+            device.device_sub_class,
+            device.device_protocol,
+        ) {
+            (Some(c), Some(s), Some(p)) => self.matches_triple(c, s, p),
+            (Some(c), _, _) => {
+                self.sub_class.is_none() && self.protocol.is_none() && c == self.class
+            }
+            _ => false,
+        };
+        device_class
+            || device
+                .interfaces
+                .iter()
+                .any(|i| self.matches_triple(i.class, i.sub_class, i.protocol))
+    }
+}
+
+impl FromStr for ClassFilter {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        let mut parts = s.split(':').map(|part| {
+            u8::from_str_radix(part, 16)
+                .with_context(|| format!("invalid class {:?}, expected e.g. ff:42:01", s))
+        });
+        let class = parts.next().context("empty class")??;
+        let sub_class = parts.next().transpose()?;
+        let protocol = parts.next().transpose()?;
+        if parts.next().is_some() {
+            bail!("invalid class {:?}, expected at most three parts", s);
+        }
+        Ok(ClassFilter {
+            class,
+            sub_class,
+            protocol,
+        })
+    }
+}
+
+impl fmt::Display for ClassFilter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:02x}", self.class)?;
+        if let Some(sub_class) = self.sub_class {
+            write!(f, ":{:02x}", sub_class)?;
+            if let Some(protocol) = self.protocol {
+                write!(f, ":{:02x}", protocol)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Criteria a device must all meet; an empty filter matches everything.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DeviceFilter {
+    pub vendor_id: Option<u16>,
+    pub product_id: Option<u16>,
+    /// Serial number, or the fallback identity of devices without one.
+    pub serial: Option<String>,
+    pub class: Option<ClassFilter>,
+    /// Device node, e.g. `/dev/bus/usb/001/002`.
+    pub path: Option<String>,
+}
+
+/// Whether to keep only the first matching device or all of them.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum MatchMode {
+    First,
+    #[default]
+    All,
+}
+
+impl DeviceFilter {
+    pub fn is_empty(&self) -> bool {
+        *self == DeviceFilter::default()
+    }
+
+    pub fn matches(&self, device: &SysfsDevice) -> bool {
+        self.path.as_ref().is_none_or(|p| *p == device.dev_path)
+            && self.vendor_id.is_none_or(|v| device.vendor_id == Some(v))
+            && self.product_id.is_none_or(|p| device.product_id == Some(p))
+            && self.serial.as_ref().is_none_or(|s| {
+                device.serial_number.as_ref() == Some(s)
+                    || device.identity().is_some_and(|id| id.matches(s))
+            })
+            && self.class.is_none_or(|c| c.matches(device))
+    }
+
+    /// Keeps the matching devices, in order, or only the first one.
+    pub fn select(&self, devices: Vec<SysfsDevice>, mode: MatchMode) -> Vec<SysfsDevice> {
+        let matching = devices.into_iter().filter(|d| self.matches(d));
+        match mode {
+            MatchMode::First => matching.take(1).collect(),
+            MatchMode::All => matching.collect(),
+        }
+    }
+}
+
+impl fmt::Display for DeviceFilter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut parts = vec![];
+        if let Some(vid) = self.vendor_id {
+            parts.push(format!("vid {:04x}", vid));
+        }
+        if let Some(pid) = self.product_id {
+            parts.push(format!("pid {:04x}", pid));
+        }
+        if let Some(serial) = &self.serial {
+            parts.push(format!("serial {}", serial));
+        }
+        if let Some(class) = self.class {
+            parts.push(format!("class {}", class));
+        }
+        if let Some(path) = &self.path {
+            parts.push(format!("path {}", path));
+        }
+        if parts.is_empty() {
+            return write!(f, "any device");
+        }
+        write!(f, "{}", parts.join(", "))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sysfs::SysfsInterface;
+
+    fn pixel() -> SysfsDevice {
+        SysfsDevice {
+            dev_path: "/dev/bus/usb/001/005".to_string(),
+            vendor_id: Some(0x18d1),
+            product_id: Some(0x4ee7),
+            device_class: Some(0),
+            device_sub_class: Some(0),
+            device_protocol: Some(0),
+            serial_number: Some("ABC".to_string()),
+            interfaces: vec![SysfsInterface {
+                class: 0xff,
+                sub_class: 0x42,
+                protocol: 0x01,
+            }],
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn parses_class() {
+        let class: ClassFilter = "ff:42:01".parse().unwrap();
+        assert_eq!(class.to_string(), "ff:42:01");
+        assert_eq!("ff".parse::<ClassFilter>().unwrap().sub_class, None);
+        assert!("ff:42:01:00".parse::<ClassFilter>().is_err());
+        assert!("zz".parse::<ClassFilter>().is_err());
+    }
+
+    #[test]
+    fn matches_criteria() {
+        let dev = pixel();
+        assert!(DeviceFilter::default().matches(&dev));
+
+        let mut filter = DeviceFilter {
+            vendor_id: Some(0x18d1),
+            product_id: Some(0x4ee7),
+            serial: Some("ABC".to_string()),
+            class: Some("ff:42:01".parse().unwrap()),
+            ..Default::default()
+        };
+        assert!(filter.matches(&dev));
+
+        filter.class = Some("ff:42:03".parse().unwrap());
+        assert!(!filter.matches(&dev));
+
+        let filter = DeviceFilter {
+            serial: Some("18d1:4ee7@1-1.2".to_string()),
+            ..Default::default()
+        };
+        let mut no_serial = SysfsDevice {
+            serial_number: None,
+            port_path: Some("1-1.2".to_string()),
+            ..pixel()
+        };
+        assert!(filter.matches(&no_serial));
+        no_serial.port_path = Some("1-1.3".to_string());
+        assert!(!filter.matches(&no_serial));
+
+        // unknown metadata never matches
+        let filter = DeviceFilter {
+            serial: Some("ABC".to_string()),
+            ..Default::default()
+        };
+        assert!(!filter.matches(&SysfsDevice::default()));
+    }
+
+    #[test]
+    fn selects_first_or_all() {
+        let mut other = pixel();
+        other.dev_path = "/dev/bus/usb/001/006".to_string();
+        let devices = vec![pixel(), other];
+        let filter = DeviceFilter {
+            vendor_id: Some(0x18d1),
+            ..Default::default()
+        };
+
+        assert_eq!(filter.select(devices.clone(), MatchMode::All).len(), 2);
+        let first = filter.select(devices, MatchMode::First);
+        assert_eq!(first, [pixel()]);
+    }
+}
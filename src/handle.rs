@@ -0,0 +1,310 @@
+//! Device I/O that works the same over libusb and raw usbfs.
+//!
+//! With the `libusb` feature (the default) fds are wrapped by libusb; without
+//! it, [`UsbfsHandle`] issues `USBDEVFS_*` ioctls on the fd directly so no C
+//! toolchain is needed for the build.
+
+use std::{io, os::fd::RawFd, time::Duration};
+
+use crate::usbfs::UsbfsHandle;
+
+/// Transfers and interface management on an open device.
+///
+/// Errors are plain [`io::Error`]s; timeouts have kind
+/// [`io::ErrorKind::TimedOut`] and stalls [`io::ErrorKind::BrokenPipe`].
+/// Handles are shared between threads, e.g. one per transfer direction.
+pub trait UsbDeviceHandle: Send + Sync {
+    fn read_control(
+        &self,
+        request_type: u8,
+        request: u8,
+        value: u16,
+        index: u16,
+        buf: &mut [u8],
+        timeout: Duration,
+    ) -> io::Result<usize>;
+
+    fn write_control(
+        &self,
+        request_type: u8,
+        request: u8,
+        value: u16,
+        index: u16,
+        buf: &[u8],
+        timeout: Duration,
+    ) -> io::Result<usize>;
+
+    fn read_bulk(&self, endpoint: u8, buf: &mut [u8], timeout: Duration) -> io::Result<usize>;
+    fn write_bulk(&self, endpoint: u8, buf: &[u8], timeout: Duration) -> io::Result<usize>;
+    fn read_interrupt(&self, endpoint: u8, buf: &mut [u8], timeout: Duration) -> io::Result<usize>;
+    fn write_interrupt(&self, endpoint: u8, buf: &[u8], timeout: Duration) -> io::Result<usize>;
+
+    fn claim_interface(&mut self, interface: u8) -> io::Result<()>;
+    fn release_interface(&mut self, interface: u8) -> io::Result<()>;
+    fn kernel_driver_active(&self, interface: u8) -> io::Result<bool>;
+    fn detach_kernel_driver(&mut self, interface: u8) -> io::Result<()>;
+    fn attach_kernel_driver(&mut self, interface: u8) -> io::Result<()>;
+    fn clear_halt(&mut self, endpoint: u8) -> io::Result<()>;
+    fn reset(&mut self) -> io::Result<()>;
+}
+
+/// Opens `usb_fd` with libusb if built with it, with usbfs otherwise.
+pub fn open_usb_handle(usb_fd: RawFd) -> anyhow::Result<Box<dyn UsbDeviceHandle>> {
+    #[cfg(feature = "libusb")]
+    return Ok(Box::new(crate::device::open_device_with_fd(usb_fd)?));
+    #[cfg(not(feature = "libusb"))]
+    return Ok(Box::new(UsbfsHandle::new(usb_fd)));
+}
+
+/// Opens `usb_fd` with the native usbfs backend regardless of features.
+pub fn open_usbfs_handle(usb_fd: RawFd) -> Box<dyn UsbDeviceHandle> {
+    Box::new(UsbfsHandle::new(usb_fd))
+}
+
+/// A fake device handle for unit tests.
+#[cfg(test)]
+pub(crate) mod testing {
+    use super::UsbDeviceHandle;
+    use std::{collections::VecDeque, io, sync::Mutex, thread, time::Duration};
+
+    /// Echoes every OUT transfer back on IN, like a loopback gadget, for tests.
+    #[derive(Default)]
+    pub(crate) struct Loopback(Mutex<VecDeque<Vec<u8>>>);
+
+    impl Loopback {
+        /// Queues a packet to be read.
+        pub(crate) fn push(&self, packet: &[u8]) {
+            self.0.lock().unwrap().push_back(packet.to_vec());
+        }
+    }
+
+    impl UsbDeviceHandle for Loopback {
+        fn read_control(
+            &self,
+            _: u8,
+            _: u8,
+            _: u16,
+            _: u16,
+            _: &mut [u8],
+            _: Duration,
+        ) -> io::Result<usize> {
+            Err(io::ErrorKind::Unsupported.into())
+        }
+
+        fn write_control(
+            &self,
+            _: u8,
+            _: u8,
+            _: u16,
+            _: u16,
+            _: &[u8],
+            _: Duration,
+        ) -> io::Result<usize> {
+            Err(io::ErrorKind::Unsupported.into())
+        }
+
+        fn read_bulk(&self, _: u8, buf: &mut [u8], _: Duration) -> io::Result<usize> {
+            let packet = self.0.lock().unwrap().pop_front();
+            match packet {
+                Some(packet) => {
+                    let n = packet.len().min(buf.len());
+                    buf[..n].copy_from_slice(&packet[..n]);
+                    Ok(n)
+                }
+                None => {
+                    thread::sleep(Duration::from_millis(5));
+                    Err(io::ErrorKind::TimedOut.into())
+                }
+            }
+        }
+
+        fn write_bulk(&self, _: u8, buf: &[u8], _: Duration) -> io::Result<usize> {
+            self.push(buf);
+            Ok(buf.len())
+        }
+
+        fn read_interrupt(
+            &self,
+            endpoint: u8,
+            buf: &mut [u8],
+            timeout: Duration,
+        ) -> io::Result<usize> {
+            self.read_bulk(endpoint, buf, timeout)
+        }
+
+        fn write_interrupt(
+            &self,
+            endpoint: u8,
+            buf: &[u8],
+            timeout: Duration,
+        ) -> io::Result<usize> {
+            self.write_bulk(endpoint, buf, timeout)
+        }
+
+        fn claim_interface(&mut self, _: u8) -> io::Result<()> {
+            Ok(())
+        }
+
+        fn release_interface(&mut self, _: u8) -> io::Result<()> {
+            Ok(())
+        }
+
+        fn kernel_driver_active(&self, _: u8) -> io::Result<bool> {
+            Ok(false)
+        }
+
+        fn detach_kernel_driver(&mut self, _: u8) -> io::Result<()> {
+            Ok(())
+        }
+
+        fn attach_kernel_driver(&mut self, _: u8) -> io::Result<()> {
+            Ok(())
+        }
+
+        fn clear_halt(&mut self, _: u8) -> io::Result<()> {
+            Ok(())
+        }
+
+        fn reset(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+}
+
+#[cfg(feature = "libusb")]
+mod libusb {
+    use super::UsbDeviceHandle;
+    use rusb::{DeviceHandle, UsbContext};
+    use std::{io, sync::Arc, time::Duration};
+
+    /// Maps libusb errors to the closest [`io::ErrorKind`].
+    pub(crate) fn io_error(e: rusb::Error) -> io::Error {
+        let kind = match e {
+            rusb::Error::Timeout => io::ErrorKind::TimedOut,
+            rusb::Error::Pipe => io::ErrorKind::BrokenPipe,
+            rusb::Error::Access => io::ErrorKind::PermissionDenied,
+            rusb::Error::NoDevice | rusb::Error::NotFound => io::ErrorKind::NotFound,
+            rusb::Error::InvalidParam => io::ErrorKind::InvalidInput,
+            rusb::Error::Interrupted => io::ErrorKind::Interrupted,
+            rusb::Error::NotSupported => io::ErrorKind::Unsupported,
+            _ => io::ErrorKind::Other,
+        };
+        io::Error::new(kind, e)
+    }
+
+    /// Implements the trait for anything that derefs to a libusb handle.
+    macro_rules! impl_libusb_handle {
+        ($handle:ty) => {
+            impl<T: UsbContext> UsbDeviceHandle for $handle {
+                fn read_control(
+                    &self,
+                    request_type: u8,
+                    request: u8,
+                    value: u16,
+                    index: u16,
+                    buf: &mut [u8],
+                    timeout: Duration,
+                ) -> io::Result<usize> {
+                    DeviceHandle::read_control(
+                        self,
+                        request_type,
+                        request,
+                        value,
+                        index,
+                        buf,
+                        timeout,
+                    )
+                    .map_err(io_error)
+                }
+
+                fn write_control(
+                    &self,
+                    request_type: u8,
+                    request: u8,
+                    value: u16,
+                    index: u16,
+                    buf: &[u8],
+                    timeout: Duration,
+                ) -> io::Result<usize> {
+                    DeviceHandle::write_control(
+                        self,
+                        request_type,
+                        request,
+                        value,
+                        index,
+                        buf,
+                        timeout,
+                    )
+                    .map_err(io_error)
+                }
+
+                fn read_bulk(
+                    &self,
+                    endpoint: u8,
+                    buf: &mut [u8],
+                    timeout: Duration,
+                ) -> io::Result<usize> {
+                    DeviceHandle::read_bulk(self, endpoint, buf, timeout).map_err(io_error)
+                }
+
+                fn write_bulk(
+                    &self,
+                    endpoint: u8,
+                    buf: &[u8],
+                    timeout: Duration,
+                ) -> io::Result<usize> {
+                    DeviceHandle::write_bulk(self, endpoint, buf, timeout).map_err(io_error)
+                }
+
+                fn read_interrupt(
+                    &self,
+                    endpoint: u8,
+                    buf: &mut [u8],
+                    timeout: Duration,
+                ) -> io::Result<usize> {
+                    DeviceHandle::read_interrupt(self, endpoint, buf, timeout).map_err(io_error)
+                }
+
+                fn write_interrupt(
+                    &self,
+                    endpoint: u8,
+                    buf: &[u8],
+                    timeout: Duration,
+                ) -> io::Result<usize> {
+                    DeviceHandle::write_interrupt(self, endpoint, buf, timeout).map_err(io_error)
+                }
+
+                fn claim_interface(&mut self, interface: u8) -> io::Result<()> {
+                    DeviceHandle::claim_interface(self, interface).map_err(io_error)
+                }
+
+                fn release_interface(&mut self, interface: u8) -> io::Result<()> {
+                    DeviceHandle::release_interface(self, interface).map_err(io_error)
+                }
+
+                fn kernel_driver_active(&self, interface: u8) -> io::Result<bool> {
+                    DeviceHandle::kernel_driver_active(self, interface).map_err(io_error)
+                }
+
+                fn detach_kernel_driver(&mut self, interface: u8) -> io::Result<()> {
+                    DeviceHandle::detach_kernel_driver(self, interface).map_err(io_error)
+                }
+
+                fn attach_kernel_driver(&mut self, interface: u8) -> io::Result<()> {
+                    DeviceHandle::attach_kernel_driver(self, interface).map_err(io_error)
+                }
+
+                fn clear_halt(&mut self, endpoint: u8) -> io::Result<()> {
+                    DeviceHandle::clear_halt(self, endpoint).map_err(io_error)
+                }
+
+                fn reset(&mut self) -> io::Result<()> {
+                    DeviceHandle::reset(self).map_err(io_error)
+                }
+            }
+        };
+    }
+
+    impl_libusb_handle!(DeviceHandle<T>);
+    impl_libusb_handle!(Arc<DeviceHandle<T>>);
+}
@@ -0,0 +1,296 @@
+//! Passing USB device fds between processes over a `UnixDatagram`.
+//!
+//! The child launched by `termux-usb -e` inherits one end of a socket pair
+//! and sends the device path along with the granted fd, framed as described
+//! in [`crate::protocol`]. The parent (or any other process holding the
+//! other end) receives a duplicate of that fd, or a [`HandoffError`]
+//! describing why there is none.
+
+use anyhow::Context;
+use libc::{fcntl, FD_CLOEXEC, F_GETFD, F_SETFD};
+use log::info;
+use nix::{errno::Errno, sys::stat::fstat};
+use sendfd::{RecvWithFd, SendWithFd};
+use serde::Serialize;
+use std::{
+    error, fmt, io,
+    os::{
+        fd::{AsRawFd, FromRawFd, IntoRawFd, OwnedFd, RawFd},
+        unix::net::UnixDatagram,
+    },
+    path::{Path, PathBuf},
+    process::ExitStatus,
+    time::Duration,
+};
+
+use crate::protocol::{
+    Message, MessageType, ERROR_BAD_FD, ERROR_CHILD_CRASHED, ERROR_DEVICE_GONE, ERROR_OTHER,
+    ERROR_PERMISSION_DENIED, ERROR_TIMEOUT, MAX_MESSAGE_LEN,
+};
+
+/// Device path and fd received from the other end of the socket.
+#[derive(Debug, Clone, Serialize)]
+pub struct ReceivedUsbFd {
+    /// Size of the received message in bytes.
+    pub size: usize,
+    /// Device path as sent by the child, e.g. `/dev/bus/usb/001/002`.
+    pub dev_path: PathBuf,
+    /// Received fd or `None` if the message carried no fd.
+    pub fd: Option<RawFd>,
+}
+
+/// Why a device fd could not be handed over.
+#[derive(Debug)]
+pub enum HandoffError {
+    /// The user declined the termux-usb permission dialog.
+    PermissionDenied {
+        dev: String,
+    },
+    /// The device was unplugged or never existed.
+    DeviceGone {
+        dev: String,
+    },
+    /// The fd given to the child is not usable.
+    BadFd {
+        dev: String,
+        reason: String,
+    },
+    /// The callback exited without sending anything.
+    ChildCrashed {
+        dev: String,
+        status: Option<ExitStatus>,
+    },
+    /// Nothing arrived within the configured timeout.
+    Timeout {
+        dev: String,
+        after: Duration,
+    },
+    /// Any other error reported by the other end.
+    Remote {
+        dev: String,
+        code: u32,
+        message: String,
+    },
+    Io(io::Error),
+}
+
+impl HandoffError {
+    /// Process exit code for this error, distinct for each kind.
+    pub fn exit_code(&self) -> u8 {
+        match self {
+            HandoffError::PermissionDenied { .. } => 3,
+            HandoffError::DeviceGone { .. } => 4,
+            HandoffError::BadFd { .. } => 5,
+            HandoffError::ChildCrashed { .. } => 6,
+            HandoffError::Timeout { .. } => 7,
+            HandoffError::Remote { .. } | HandoffError::Io(_) => 1,
+        }
+    }
+
+    /// Error code used for this error in a [`MessageType::Error`] message.
+    pub fn wire_code(&self) -> u32 {
+        match self {
+            HandoffError::PermissionDenied { .. } => ERROR_PERMISSION_DENIED,
+            HandoffError::DeviceGone { .. } => ERROR_DEVICE_GONE,
+            HandoffError::BadFd { .. } => ERROR_BAD_FD,
+            HandoffError::ChildCrashed { .. } => ERROR_CHILD_CRASHED,
+            HandoffError::Timeout { .. } => ERROR_TIMEOUT,
+            HandoffError::Remote { code, .. } => *code,
+            HandoffError::Io(_) => ERROR_OTHER,
+        }
+    }
+
+    /// Reconstructs the error carried by a [`MessageType::Error`] message.
+    pub fn from_message(msg: &Message) -> Self {
+        let dev = msg
+            .dev_path
+            .as_ref()
+            .map(|p| p.display().to_string())
+            .unwrap_or_default();
+        let message = msg.error_message.clone().unwrap_or_default();
+        match msg.error_code.unwrap_or(ERROR_OTHER) {
+            ERROR_PERMISSION_DENIED => HandoffError::PermissionDenied { dev },
+            ERROR_DEVICE_GONE => HandoffError::DeviceGone { dev },
+            ERROR_BAD_FD => HandoffError::BadFd {
+                dev,
+                reason: message,
+            },
+            ERROR_CHILD_CRASHED => HandoffError::ChildCrashed { dev, status: None },
+            ERROR_TIMEOUT => HandoffError::Timeout {
+                dev,
+                after: Duration::ZERO,
+            },
+            code => HandoffError::Remote { dev, code, message },
+        }
+    }
+
+    /// Encodes the error as a message for the other end of the socket.
+    pub fn to_message(&self) -> Message {
+        let dev = match self {
+            HandoffError::PermissionDenied { dev }
+            | HandoffError::DeviceGone { dev }
+            | HandoffError::BadFd { dev, .. }
+            | HandoffError::ChildCrashed { dev, .. }
+            | HandoffError::Timeout { dev, .. }
+            | HandoffError::Remote { dev, .. } => Some(Path::new(dev.as_str())),
+            HandoffError::Io(_) => None,
+        };
+        let message = match self {
+            HandoffError::BadFd { reason, .. } => reason.clone(),
+            HandoffError::Remote { message, .. } => message.clone(),
+            e => e.to_string(),
+        };
+        Message::error(dev, self.wire_code(), &message)
+    }
+}
+
+impl fmt::Display for HandoffError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HandoffError::PermissionDenied { dev } => write!(f, "permission denied for {}", dev),
+            HandoffError::DeviceGone { dev } => write!(f, "device {} is gone", dev),
+            HandoffError::BadFd { dev, reason } => write!(f, "bad fd for {}: {}", dev, reason),
+            HandoffError::ChildCrashed { dev, status } => match status {
+                Some(status) => write!(
+                    f,
+                    "termux-usb callback for {} sent nothing ({})",
+                    dev, status
+                ),
+                None => write!(f, "termux-usb callback for {} sent nothing", dev),
+            },
+            HandoffError::Timeout { dev, after } => {
+                write!(f, "timed out after {:?} waiting for {}", after, dev)
+            }
+            HandoffError::Remote { dev, code, message } => {
+                write!(f, "error {} for {}: {}", code, dev, message)
+            }
+            HandoffError::Io(e) => write!(f, "socket error: {}", e),
+        }
+    }
+}
+
+impl error::Error for HandoffError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            HandoffError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for HandoffError {
+    fn from(e: io::Error) -> Self {
+        HandoffError::Io(e)
+    }
+}
+
+/// Clears `FD_CLOEXEC` on the socket so it survives exec into a child.
+pub fn clear_cloexec_flag(socket: &UnixDatagram) -> RawFd {
+    let sock_fd = socket.as_raw_fd();
+    unsafe {
+        let flags = fcntl(sock_fd, F_GETFD);
+        fcntl(sock_fd, F_SETFD, flags & !FD_CLOEXEC);
+    }
+    sock_fd
+}
+
+/// Sends a [`MessageType::DeviceFd`] message for `dev_path` with `usb_fd`
+/// attached over `socket`.
+pub fn send_usb_fd(socket: &UnixDatagram, dev_path: &str, usb_fd: RawFd) -> io::Result<usize> {
+    let msg = Message::device_fd(Path::new(dev_path), 1)
+        .encode()
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    socket.send_with_fd(&msg, &[usb_fd])
+}
+
+/// Receives a device path and fd sent by [`send_usb_fd`].
+///
+/// Error messages from the other end are turned into the matching
+/// [`HandoffError`]. Messages that cannot be decoded are reported as
+/// [`io::ErrorKind::InvalidData`]; any fd attached to them is closed.
+pub fn recv_usb_fd(socket: &UnixDatagram) -> Result<ReceivedUsbFd, HandoffError> {
+    let mut buf = vec![0; MAX_MESSAGE_LEN];
+    let mut fds = vec![0; 1];
+    let (size, fd_count) = socket.recv_with_fd(buf.as_mut_slice(), fds.as_mut_slice())?;
+    let fd = (fd_count > 0).then(|| unsafe { OwnedFd::from_raw_fd(fds[0]) });
+
+    let msg = Message::decode(&buf[0..size])
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    match msg.kind {
+        MessageType::DeviceFd => Ok(ReceivedUsbFd {
+            size,
+            dev_path: msg.dev_path.unwrap_or_default(),
+            fd: fd.map(IntoRawFd::into_raw_fd),
+        }),
+        MessageType::Error => Err(HandoffError::from_message(&msg)),
+        kind => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("unexpected {:?} message", kind),
+        )
+        .into()),
+    }
+}
+
+/// Checks that `termux_usb_fd` names an open fd of a present device.
+fn check_usb_fd(termux_usb_dev: &str, termux_usb_fd: &str) -> Result<RawFd, HandoffError> {
+    let bad_fd = |reason: String| HandoffError::BadFd {
+        dev: termux_usb_dev.to_string(),
+        reason,
+    };
+    let usb_fd = termux_usb_fd
+        .parse()
+        .map_err(|_| bad_fd(format!("invalid fd {:?}", termux_usb_fd)))?;
+    match fstat(usb_fd) {
+        Ok(_) => Ok(usb_fd),
+        Err(Errno::ENODEV) | Err(Errno::ENXIO) => Err(HandoffError::DeviceGone {
+            dev: termux_usb_dev.to_string(),
+        }),
+        Err(e) => Err(bad_fd(format!("fd {}: {}", usb_fd, e))),
+    }
+}
+
+/// Sends `termux_usb_fd` for `termux_usb_dev` over `socket`, or an error
+/// message if the fd is unusable or cannot be sent.
+///
+/// This is the body of the `termux-usb -e` callback, usable in-process.
+pub fn hand_over_usb_fd(
+    socket: &UnixDatagram,
+    termux_usb_dev: &str,
+    termux_usb_fd: &str,
+) -> Result<(), HandoffError> {
+    let sent = check_usb_fd(termux_usb_dev, termux_usb_fd)
+        .and_then(|usb_fd| Ok(send_usb_fd(socket, termux_usb_dev, usb_fd)?));
+    match sent {
+        Ok(_) => {
+            info!(
+                "found {}, sending fd {} to parent",
+                &termux_usb_dev, &termux_usb_fd
+            );
+            Ok(())
+        }
+        Err(e) => {
+            eprintln!("error sending usb fd to parent: {}", e);
+            if let Ok(msg) = e.to_message().encode() {
+                _ = socket.send(&msg);
+            }
+            Err(e)
+        }
+    }
+}
+
+/// Child side of the handoff, run from the `termux-usb -e` callback.
+///
+/// All arguments are taken verbatim from the environment variables set by
+/// `termux-usb` (`TERMUX_USB_DEV`, `TERMUX_USB_FD`) and by the parent
+/// (`TERMUX_ADB_SOCK_FD`). Failures are reported to the parent as an error
+/// message before being returned.
+pub fn sendfd_to_adb(
+    termux_usb_dev: &str,
+    termux_usb_fd: &str,
+    sock_send_fd: &str,
+) -> anyhow::Result<()> {
+    let socket =
+        unsafe { UnixDatagram::from_raw_fd(sock_send_fd.parse().context("invalid socket fd")?) };
+    // send termux_usb_dev and termux_usb_fd to adb-hooks
+    Ok(hand_over_usb_fd(&socket, termux_usb_dev, termux_usb_fd)?)
+}
@@ -0,0 +1,97 @@
+//! Hex input and output for transfer payloads.
+
+use serde::Serializer;
+use std::fmt::Write;
+
+/// Parses bytes written as hex, e.g. `0102ff`, `01 02 ff` or `0x01,0x02`.
+/// Spaces, commas and colons separate groups; each group has an even
+/// number of digits.
+pub fn parse_hex_bytes(s: &str) -> Result<Vec<u8>, String> {
+    let mut bytes = vec![];
+    let groups = s
+        .split(|c: char| c.is_whitespace() || c == ',' || c == ':')
+        .filter(|g| !g.is_empty());
+    for group in groups {
+        let digits = group.trim_start_matches("0x");
+        if digits.len() % 2 != 0 || !digits.is_ascii() {
+            return Err(format!("odd number of hex digits in {:?}", group));
+        }
+        for i in (0..digits.len()).step_by(2) {
+            let byte = u8::from_str_radix(&digits[i..i + 2], 16)
+                .map_err(|e| format!("invalid hex {:?}: {}", group, e))?;
+            bytes.push(byte);
+        }
+    }
+    Ok(bytes)
+}
+
+/// Formats bytes as a plain lowercase hex string.
+pub fn encode(data: &[u8]) -> String {
+    data.iter().fold(String::new(), |mut s, b| {
+        let _ = write!(s, "{:02x}", b);
+        s
+    })
+}
+
+/// Serializes bytes as a hex string rather than an array of numbers.
+pub fn serialize<S: Serializer>(data: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(&encode(data))
+}
+
+/// Formats bytes like `hexdump -C`: offset, 16 bytes in hex, then ASCII.
+pub fn hexdump(data: &[u8]) -> String {
+    let mut out = String::new();
+    for (i, line) in data.chunks(16).enumerate() {
+        let _ = write!(out, "{:08x} ", i * 16);
+        for j in 0..16 {
+            if j == 8 {
+                out.push(' ');
+            }
+            match line.get(j) {
+                Some(b) => {
+                    let _ = write!(out, " {:02x}", b);
+                }
+                None => out.push_str("   "),
+            }
+        }
+        let ascii: String = line
+            .iter()
+            .map(|&b| {
+                if b.is_ascii_graphic() || b == b' ' {
+                    b as char
+                } else {
+                    '.'
+                }
+            })
+            .collect();
+        let _ = writeln!(out, "  |{}|", ascii);
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_hex_groups() {
+        assert_eq!(parse_hex_bytes("0102ff").unwrap(), [1, 2, 0xff]);
+        assert_eq!(
+            parse_hex_bytes("0x01, 0x02 ff:0A").unwrap(),
+            [1, 2, 0xff, 0x0a]
+        );
+        assert!(parse_hex_bytes("").unwrap().is_empty());
+        assert!(parse_hex_bytes("123").is_err());
+        assert!(parse_hex_bytes("zz").is_err());
+    }
+
+    #[test]
+    fn dumps_like_hexdump_c() {
+        let dump = hexdump(b"0123456789abcdefXY\x00");
+        assert_eq!(
+            dump,
+            "00000000  30 31 32 33 34 35 36 37  38 39 61 62 63 64 65 66  |0123456789abcdef|\n\
+             00000010  58 59 00                                          |XY.|\n"
+        );
+    }
+}
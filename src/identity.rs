@@ -0,0 +1,118 @@
+//! Stable names for devices, with or without a serial number.
+//!
+//! Many cheap serial adapters and hubs have no serial string. Those are
+//! named by `VID:PID@port`, where the port chain (e.g. `1-1.2`) stays the
+//! same as long as the device is plugged into the same socket. A short hash
+//! of the name is accepted as well, for tools that expect serial-like ids.
+
+use serde::Serialize;
+use std::fmt;
+
+/// Identity of a device as accepted wherever a serial number is.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct DeviceIdentity {
+    /// Serial number if present, otherwise `VID:PID@port`.
+    pub id: String,
+    /// `usb-` followed by a hash of `id`.
+    pub hash: String,
+    pub serial_number: Option<String>,
+    pub vendor_id: u16,
+    pub product_id: u16,
+    /// Bus and port chain like `1-1.2`, if known.
+    pub port_path: Option<String>,
+}
+
+impl DeviceIdentity {
+    pub fn new(
+        serial_number: Option<String>,
+        vendor_id: u16,
+        product_id: u16,
+        port_path: Option<String>,
+    ) -> Self {
+        // an empty serial string is as good as none
+        let serial_number = serial_number.filter(|s| !s.trim().is_empty());
+        let id = match (&serial_number, &port_path) {
+            (Some(serial), _) => serial.clone(),
+            (None, Some(port)) => format!("{:04x}:{:04x}@{}", vendor_id, product_id, port),
+            (None, None) => format!("{:04x}:{:04x}", vendor_id, product_id),
+        };
+        DeviceIdentity {
+            hash: format!("usb-{:016x}", fnv1a(id.as_bytes())),
+            id,
+            serial_number,
+            vendor_id,
+            product_id,
+            port_path,
+        }
+    }
+
+    /// False if the device has neither a serial number nor a known port,
+    /// i.e. several identical devices would share this identity.
+    pub fn is_unique(&self) -> bool {
+        self.serial_number.is_some() || self.port_path.is_some()
+    }
+
+    /// True if `s` names this device by serial, fallback id or hash.
+    /// Serials are case-sensitive; only the hex ids of the fallback are not.
+    pub fn matches(&self, s: &str) -> bool {
+        let id_matches = match &self.serial_number {
+            Some(_) => self.id == s,
+            None => self.id.eq_ignore_ascii_case(s),
+        };
+        id_matches || self.hash == s
+    }
+}
+
+impl fmt::Display for DeviceIdentity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.id)
+    }
+}
+
+/// Port chain of a libusb device in sysfs notation, e.g. `1-1.2`.
+#[cfg(feature = "libusb")]
+pub fn port_path<T: rusb::UsbContext>(device: &rusb::Device<T>) -> Option<String> {
+    let ports = device.port_numbers().ok().filter(|p| !p.is_empty())?;
+    let ports: Vec<_> = ports.iter().map(u8::to_string).collect();
+    Some(format!("{}-{}", device.bus_number(), ports.join(".")))
+}
+
+/// 64-bit FNV-1a, stable across Rust releases unlike `DefaultHasher`.
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf2_9ce4_8422_2325, |hash, &b| {
+        (hash ^ u64::from(b)).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn prefers_serial() {
+        let identity = DeviceIdentity::new(Some("ABC".into()), 0x18d1, 0x4ee7, Some("1-1".into()));
+        assert_eq!(identity.id, "ABC");
+        assert!(identity.matches("ABC"));
+        assert!(!identity.matches("abc"));
+        assert!(identity.matches(&identity.hash.clone()));
+    }
+
+    #[test]
+    fn falls_back_to_port_chain() {
+        let identity = DeviceIdentity::new(Some(" ".into()), 0x1a86, 0x7523, Some("1-1.2".into()));
+        assert_eq!(identity.id, "1a86:7523@1-1.2");
+        assert!(identity.is_unique());
+        assert!(identity.matches("1A86:7523@1-1.2"));
+        assert_eq!(
+            identity.hash,
+            format!("usb-{:016x}", fnv1a(b"1a86:7523@1-1.2"))
+        );
+
+        let other = DeviceIdentity::new(None, 0x1a86, 0x7523, Some("1-1.3".into()));
+        assert_ne!(identity.hash, other.hash);
+
+        let identity = DeviceIdentity::new(None, 0x1a86, 0x7523, None);
+        assert_eq!(identity.id, "1a86:7523");
+        assert!(!identity.is_unique());
+    }
+}
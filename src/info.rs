@@ -0,0 +1,220 @@
+//! Human readable summary of a device descriptor.
+
+use libc::c_int;
+use serde::Serialize;
+use std::fmt;
+
+use crate::{
+    identity::DeviceIdentity,
+    rawdesc::read_descriptors_from_fd,
+    resolve::ResolvedNode,
+    strings::StringReader,
+    usbfs::{self, UsbfsHandle},
+};
+#[cfg(not(feature = "libusb"))]
+use crate::{resolve::resolve_node, sysfs::Sysfs};
+#[cfg(feature = "libusb")]
+use {
+    crate::{device::open_device_with_fd, identity, strings::DEFAULT_LANGUAGES},
+    anyhow::Context,
+    rusb::{DeviceHandle, Speed, UsbContext, Version},
+};
+
+/// Contents of the device descriptor plus the strings it refers to.
+#[derive(Debug, Clone, Serialize)]
+pub struct UsbDeviceInfo {
+    pub vendor_id: u16,
+    pub product_id: u16,
+    /// USB specification release in BCD, e.g. `0x0200`.
+    pub usb_version: u16,
+    /// Device release number in BCD.
+    pub device_version: u16,
+    pub class_code: u8,
+    pub sub_class_code: u8,
+    pub protocol_code: u8,
+    pub max_packet_size: u8,
+    pub num_configurations: u8,
+    pub manufacturer: Option<String>,
+    pub product: Option<String>,
+    pub serial_number: Option<String>,
+    /// Negotiated speed, e.g. `high`.
+    pub speed: String,
+    /// Serial number or, without one, the `VID:PID@port` fallback.
+    pub identity: DeviceIdentity,
+}
+
+/// Opens the device behind `usb_fd` and reads its [`UsbDeviceInfo`].
+#[cfg(feature = "libusb")]
+pub fn init_libusb_device_info(usb_fd: c_int) -> anyhow::Result<UsbDeviceInfo> {
+    let usb_handle = open_device_with_fd(usb_fd)?;
+    read_device_info(&usb_handle, DEFAULT_LANGUAGES)
+}
+
+/// Reads the device descriptor and its strings through an open handle,
+/// preferring the given LANGIDs.
+///
+/// Missing or unreadable strings are reported as `None` rather than failing
+/// the whole query.
+#[cfg(feature = "libusb")]
+pub fn read_device_info<T: UsbContext>(
+    usb_handle: &DeviceHandle<T>,
+    languages: &[u16],
+) -> anyhow::Result<UsbDeviceInfo> {
+    let usb_dev = usb_handle.device();
+    let desc = usb_dev
+        .device_descriptor()
+        .context("error getting device descriptor")?;
+
+    let strings = StringReader::new(usb_handle, languages);
+    let manufacturer = strings.read(desc.manufacturer_string_index(), "manufacturer");
+    let product = strings.read(desc.product_string_index(), "product");
+    let serial_number = strings.read(desc.serial_number_string_index(), "serial number");
+
+    Ok(UsbDeviceInfo {
+        vendor_id: desc.vendor_id(),
+        product_id: desc.product_id(),
+        usb_version: version_to_bcd(desc.usb_version()),
+        device_version: version_to_bcd(desc.device_version()),
+        class_code: desc.class_code(),
+        sub_class_code: desc.sub_class_code(),
+        protocol_code: desc.protocol_code(),
+        max_packet_size: desc.max_packet_size(),
+        num_configurations: desc.num_configurations(),
+        manufacturer,
+        product,
+        identity: DeviceIdentity::new(
+            serial_number.clone(),
+            desc.vendor_id(),
+            desc.product_id(),
+            identity::port_path(&usb_dev),
+        ),
+        serial_number,
+        speed: speed_name(usb_dev.speed()).to_string(),
+    })
+}
+
+/// Reads the [`UsbDeviceInfo`] of the device behind `usb_fd` with libusb if
+/// built with it, with usbfs otherwise.
+pub fn init_device_info(usb_fd: c_int, languages: &[u16]) -> anyhow::Result<UsbDeviceInfo> {
+    #[cfg(feature = "libusb")]
+    return read_device_info(&*open_device_with_fd(usb_fd)?, languages);
+    #[cfg(not(feature = "libusb"))]
+    return init_usbfs_device_info(
+        usb_fd,
+        &resolve_node(usb_fd, None, &Sysfs::default()),
+        languages,
+    );
+}
+
+/// Reads the [`UsbDeviceInfo`] of the device behind `usb_fd` with usbfs
+/// ioctls and the descriptors the kernel caches, without libusb.
+///
+/// The port path comes from the sysfs directory in `node`, as found by
+/// [`resolve_node`], so it is missing where sysfs is hidden.
+pub fn init_usbfs_device_info(
+    usb_fd: c_int,
+    node: &ResolvedNode,
+    languages: &[u16],
+) -> anyhow::Result<UsbDeviceInfo> {
+    let raw = read_descriptors_from_fd(usb_fd)?;
+    let desc = &raw.device;
+    let handle = UsbfsHandle::new(usb_fd);
+    let strings = StringReader::new(&handle, languages);
+    let index = |i: u8| (i != 0).then_some(i);
+    let manufacturer = strings.read(index(desc.manufacturer_index), "manufacturer");
+    let product = strings.read(index(desc.product_index), "product");
+    let serial_number = strings.read(index(desc.serial_number_index), "serial number");
+
+    let port_path = node
+        .sysfs_path
+        .as_ref()
+        .and_then(|dir| Some(dir.file_name()?.to_string_lossy().into_owned()));
+
+    Ok(UsbDeviceInfo {
+        vendor_id: desc.vendor_id,
+        product_id: desc.product_id,
+        usb_version: desc.usb_version,
+        device_version: desc.device_version,
+        class_code: desc.class_code,
+        sub_class_code: desc.sub_class_code,
+        protocol_code: desc.protocol_code,
+        max_packet_size: desc.max_packet_size,
+        num_configurations: desc.num_configurations,
+        manufacturer,
+        product,
+        identity: DeviceIdentity::new(
+            serial_number.clone(),
+            desc.vendor_id,
+            desc.product_id,
+            port_path,
+        ),
+        serial_number,
+        speed: usbfs::speed(usb_fd).unwrap_or("unknown").to_string(),
+    })
+}
+
+/// Converts a decoded [`Version`] back to its BCD representation.
+#[cfg(feature = "libusb")]
+pub fn version_to_bcd(version: Version) -> u16 {
+    // rusb decodes the major number from two BCD digits
+    let major = u16::from(version.major());
+    ((major / 10) << 12)
+        | ((major % 10) << 8)
+        | (u16::from(version.minor() & 0x0f) << 4)
+        | u16::from(version.sub_minor() & 0x0f)
+}
+
+/// Short name of a link speed as used by lsusb and sysfs.
+#[cfg(feature = "libusb")]
+pub fn speed_name(speed: Speed) -> &'static str {
+    match speed {
+        Speed::Low => "low",
+        Speed::Full => "full",
+        Speed::High => "high",
+        Speed::Super => "super",
+        Speed::SuperPlus => "super+",
+        _ => "unknown",
+    }
+}
+
+/// Formats a BCD encoded version as `major.minor`, e.g. `2.00`.
+pub(crate) fn fmt_bcd(bcd: u16) -> String {
+    format!("{:x}.{:02x}", bcd >> 8, bcd & 0xff)
+}
+
+impl fmt::Display for UsbDeviceInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let opt = |s: &Option<String>| s.clone().unwrap_or_default();
+        writeln!(f, "Device Descriptor:")?;
+        writeln!(f, "  bcdUSB             {:>6}", fmt_bcd(self.usb_version))?;
+        writeln!(f, "  bDeviceClass       {:>6}", self.class_code)?;
+        writeln!(f, "  bDeviceSubClass    {:>6}", self.sub_class_code)?;
+        writeln!(f, "  bDeviceProtocol    {:>6}", self.protocol_code)?;
+        writeln!(f, "  bMaxPacketSize0    {:>6}", self.max_packet_size)?;
+        writeln!(f, "  idVendor           0x{:04x}", self.vendor_id)?;
+        writeln!(f, "  idProduct          0x{:04x}", self.product_id)?;
+        writeln!(
+            f,
+            "  bcdDevice          {:>6}",
+            fmt_bcd(self.device_version)
+        )?;
+        writeln!(f, "  iManufacturer      {}", opt(&self.manufacturer))?;
+        writeln!(f, "  iProduct           {}", opt(&self.product))?;
+        writeln!(f, "  iSerial            {}", opt(&self.serial_number))?;
+        writeln!(f, "  bNumConfigurations {:>6}", self.num_configurations)?;
+        writeln!(f, "  Speed              {}", self.speed)
+    }
+}
+
+#[cfg(all(test, feature = "libusb"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trips_bcd_versions() {
+        for bcd in [0x0110, 0x0200, 0x0321, 0x1000, 0x1234, 0x9999] {
+            assert_eq!(version_to_bcd(Version::from_bcd(bcd)), bcd);
+        }
+        assert_eq!(fmt_bcd(version_to_bcd(Version::from_bcd(0x1234))), "12.34");
+    }
+}
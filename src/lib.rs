@@ -0,0 +1,85 @@
+//! Access to USB devices granted through `termux-usb`.
+//!
+//! On unrooted Android the only way to talk to a USB device is to let
+//! `termux-usb` ask the user for permission and hand over an open file
+//! descriptor. This crate wraps that dance so the fd can be requested once,
+//! passed over a Unix domain socket to whoever needs it (e.g. adb-hooks)
+//! and opened with libusb there, or with raw usbfs ioctls when built
+//! without the `libusb` feature.
+//!
+//! - [`termux`] lists devices and runs callbacks under `termux-usb`,
+//! - [`backend`] abstracts that behind a trait with a fake for testing,
+//! - [`handoff`] passes device fds between processes over a `UnixDatagram`,
+//! - [`protocol`] defines the framing of messages sent over those sockets,
+//! - [`broker`] keeps granted fds open and shares them with local clients,
+//! - [`context`] shares one libusb context among all wrapped fds,
+//! - [`device`] opens devices from fds and resolves their serials,
+//! - [`control`] issues arbitrary control requests,
+//! - [`usbcat`] streams stdin and stdout through bulk endpoints,
+//! - [`monitor`] watches interrupt IN endpoints,
+//! - [`handle`] does transfers the same way over libusb and usbfs,
+//! - [`identity`] names devices that have no serial number,
+//! - [`resolve`] finds the node and sysfs entry behind an fd,
+//! - [`usbfs`] wraps raw usbfs ioctls and validates device fds,
+//! - [`sysfs`] describes listed devices before permission is granted,
+//! - [`filter`] selects devices by that metadata,
+//! - [`info`] reports the contents of the device descriptor,
+//! - [`strings`] reads string descriptors in a preferred language,
+//! - [`descriptors`] walks configurations, interfaces and endpoints,
+//! - [`rawdesc`] decodes the same from raw bytes without libusb,
+//! - [`hex`] parses and dumps transfer payloads.
+
+pub mod backend;
+pub mod broker;
+#[cfg(feature = "libusb")]
+pub mod context;
+pub mod control;
+pub mod descriptors;
+pub mod device;
+pub mod filter;
+pub mod handle;
+pub mod handoff;
+pub mod hex;
+pub mod identity;
+pub mod info;
+pub mod monitor;
+pub mod protocol;
+pub mod rawdesc;
+pub mod resolve;
+pub mod strings;
+pub mod sysfs;
+pub mod termux;
+pub mod usbcat;
+pub mod usbfs;
+
+pub use backend::{FakeBackend, TermuxUsb, UsbPermissionBackend};
+pub use control::{control_transfer, ControlData, ControlRequest, ControlResponse};
+pub use descriptors::UsbDescriptorTree;
+#[cfg(feature = "libusb")]
+pub use descriptors::{init_libusb_descriptor_tree, read_descriptor_tree};
+pub use device::{
+    init_device_serial, init_usbfs_device_serial, SerialCheck, TermuxUsbDevice, UsbSerial,
+};
+#[cfg(feature = "libusb")]
+pub use device::{init_libusb_device_serial, open_device_with_fd};
+pub use filter::{ClassFilter, DeviceFilter, MatchMode};
+pub use handle::{open_usb_handle, open_usbfs_handle, UsbDeviceHandle};
+pub use handoff::{recv_usb_fd, send_usb_fd, sendfd_to_adb, HandoffError, ReceivedUsbFd};
+pub use identity::DeviceIdentity;
+pub use info::{init_device_info, init_usbfs_device_info, UsbDeviceInfo};
+#[cfg(feature = "libusb")]
+pub use info::{init_libusb_device_info, read_device_info};
+pub use monitor::{
+    find_interrupt_endpoint, monitor_interrupts, write_report, InterruptReport, MonitorOptions,
+};
+pub use rawdesc::{
+    parse_descriptors, read_descriptors_from_fd, read_descriptors_from_sysfs, RawDescriptors,
+};
+pub use resolve::{resolve_node, ResolvedNode};
+pub use strings::{StringReader, UsbStringTable, DEFAULT_LANGUAGES};
+pub use termux::{
+    get_termux_usb_list, list_termux_usb_devices, request_usb_fd, run_under_termux_usb,
+    usb_fd_from_env, ListError, ListedDevice,
+};
+pub use usbcat::{usbcat, BulkEndpoint, CatCounters, CatOptions};
+pub use usbfs::UsbfsHandle;
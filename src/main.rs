@@ -1,232 +1,624 @@
-use anyhow::Context;
-use libc::{c_int, fcntl, FD_CLOEXEC, F_GETFD, F_SETFD};
-use log::{debug, info};
-use nix::{
-    fcntl::readlink,
-    sys::stat::fstat,
-    unistd::{lseek, Whence},
-};
-use rusb::{constants::LIBUSB_OPTION_NO_DEVICE_DISCOVERY, UsbContext};
-use sendfd::{RecvWithFd, SendWithFd};
+use anyhow::{bail, Context, Error};
+use clap::{CommandFactory, Parser};
+use libc::c_int;
+use log::debug;
+use nix::sys::signal::{sigaction, SaFlags, SigAction, SigHandler, SigSet, Signal};
+use serde::Serialize;
 use std::{
-    env, io,
-    os::{
-        fd::{AsRawFd, FromRawFd, RawFd},
-        unix::net::UnixDatagram,
-    },
-    path::PathBuf,
-    process::{Command, ExitStatus},
-    ptr::null_mut,
-    str,
-    time::Duration,
+    env, fmt, fs,
+    io::{self, Read, Write},
+    os::{fd::IntoRawFd, unix::net::UnixDatagram},
+    process::ExitCode,
+    sync::atomic::{AtomicBool, Ordering},
+};
+use termux_usb::{
+    broker::{bind_broker_socket, request_from_broker, Broker},
+    control_transfer,
+    descriptors::{EndpointDirection, TransferType},
+    find_interrupt_endpoint, hex, monitor_interrupts, open_usb_handle, read_descriptors_from_fd,
+    read_descriptors_from_sysfs, resolve_node, sendfd_to_adb,
+    sysfs::{Sysfs, SysfsDevice},
+    termux::{TERMUX_ADB_SOCK_FD, TERMUX_USB_DEV, TERMUX_USB_FD},
+    write_report, BulkEndpoint, ControlData, ControlRequest, FakeBackend, HandoffError,
+    MonitorOptions, RawDescriptors, ReceivedUsbFd, StringReader, TermuxUsb, TermuxUsbDevice,
+    UsbDeviceInfo, UsbPermissionBackend, UsbSerial, DEFAULT_LANGUAGES,
 };
 
-#[derive(Debug, Clone)]
-#[allow(dead_code)]
-struct UsbSerial {
-    number: String,
-    path: PathBuf,
+mod cli;
+
+use cli::{
+    Cli, Command, ControlArgs, DescriptorArgs, DeviceArgs, FilterArgs, MonitorArgs, ServeArgs,
+    UsbcatArgs,
+};
+
+/// Outcome of requesting one device from `termux-usb`, as printed by `--json`.
+#[derive(Debug, Serialize)]
+struct HandoffReport {
+    device: String,
+    received: Option<ReceivedUsbFd>,
+    info: Option<UsbDeviceInfo>,
+    serial: Option<UsbSerial>,
+    error: Option<String>,
 }
 
-fn init_libusb_device_serial(usb_fd: c_int) -> anyhow::Result<UsbSerial> {
-    debug!("calling libusb_set_option");
-    unsafe { rusb::ffi::libusb_set_option(null_mut(), LIBUSB_OPTION_NO_DEVICE_DISCOVERY) };
+#[derive(Debug, Serialize)]
+struct UdsReport {
+    devices: Vec<String>,
+    results: Vec<HandoffReport>,
+}
 
-    lseek(usb_fd, 0, Whence::SeekSet).with_context(|| format!("error seeking fd: {}", usb_fd))?;
+#[derive(Debug, Serialize)]
+struct DeviceReport<'a> {
+    descriptors: &'a RawDescriptors,
+    serial: UsbSerial,
+}
 
-    let ctx = rusb::Context::new().context("libusb_init error")?;
+fn print_json<T: Serialize>(value: &T) -> anyhow::Result<()> {
+    let out = serde_json::to_string_pretty(value).context("error serializing output")?;
+    println!("{}", out);
+    Ok(())
+}
 
-    debug!("opening device from {}", usb_fd);
-    let usb_handle = unsafe {
-        ctx.open_device_with_fd(usb_fd)
-            .context("error opening device")
-    }?;
+/// Picks termux-usb or, with --fake-device, the fake backend.
+fn backend(cli: &Cli) -> anyhow::Result<Box<dyn UsbPermissionBackend>> {
+    if !cli.fake_device.is_empty() {
+        return Ok(Box::new(FakeBackend::new(&cli.fake_device)));
+    }
+    let self_path = env::current_exe().context("failed to get executable path")?;
+    Ok(Box::new(TermuxUsb::new(&self_path, cli.request_timeout())))
+}
 
-    debug!("getting device from handle");
-    let usb_dev = usb_handle.device();
+/// Lists devices with their sysfs metadata and keeps those matching
+/// `args`, without requesting permission for any of them.
+fn matching_devices(
+    cli: &Cli,
+    backend: &dyn UsbPermissionBackend,
+    args: &FilterArgs,
+) -> anyhow::Result<Vec<SysfsDevice>> {
+    let sysfs = Sysfs::new(&cli.sysfs_root);
+    let filter = args.filter();
+    let devices: Vec<_> = backend
+        .list_devices()?
+        .iter()
+        .map(|dev| sysfs.device(dev))
+        .collect();
+    for dev in &devices {
+        for reason in &dev.unreadable {
+            debug!("{}: {}", dev.dev_path, reason);
+        }
+    }
+    let selected = filter.select(devices, args.mode());
+    debug!("{} device(s) match {}", selected.len(), filter);
+    Ok(selected)
+}
 
-    debug!("requesting device descriptor");
-    let usb_dev_desc = usb_dev
-        .device_descriptor()
-        .context("error getting device descriptor")?;
+/// Gets the fd of the device to work with, either from a broker, by asking
+/// the backend for `args.device` or from TERMUX_USB_FD when run as a
+/// termux-usb callback.
+fn resolve_usb_fd(cli: &Cli, args: &DeviceArgs) -> anyhow::Result<c_int> {
+    let filter = args.filter.filter();
+    if !filter.is_empty() && (args.device.is_some() || args.broker) {
+        bail!("filter options cannot be combined with a device or --broker");
+    }
 
-    let vid = usb_dev_desc.vendor_id();
-    let pid = usb_dev_desc.product_id();
-    let iser = usb_dev_desc.serial_number_string_index();
-    debug!(
-        "device descriptor: vid={}, pid={}, iSerial={}",
-        vid,
-        pid,
-        iser.unwrap_or(0)
-    );
+    if args.broker {
+        let selector = args
+            .device
+            .as_deref()
+            .context("--broker needs a device path, VID:PID or serial number")?;
+        let (_, fd) = request_from_broker(&args.socket, selector)?;
+        return Ok(fd.into_raw_fd());
+    }
 
-    let timeout = Duration::from_secs(1);
-    let languages = usb_handle
-        .read_languages(timeout)
-        .context("error getting supported languages for reading string descriptors")?;
+    let backend = backend(cli)?;
+    let dev = if filter.is_empty() {
+        args.device.clone()
+    } else {
+        let mut devices = matching_devices(cli, backend.as_ref(), &args.filter)?;
+        if devices.is_empty() {
+            bail!("no listed device matches {}", filter);
+        }
+        Some(devices.swap_remove(0).dev_path)
+    };
+    let Some(dev) = &dev else {
+        if env::var_os(TERMUX_USB_FD).is_none() {
+            bail!(
+                "no device given and {} is not set; pass a device path \
+                 (see `list`) or run under termux-usb -e ... -E",
+                TERMUX_USB_FD
+            );
+        }
+        return termux_usb::usb_fd_from_env();
+    };
 
-    let serial_number = usb_handle
-        .read_serial_number_string(languages[0], &usb_dev_desc, timeout)
-        .context("error reading serial number of the device")?;
+    let (sock_send, sock_recv) = UnixDatagram::pair().context("could not create socket pair")?;
+    let msg = backend.request_fd(dev, &sock_send, &sock_recv)?;
+    msg.fd
+        .with_context(|| format!("no fd was handed over for {}", dev))
+}
 
-    let st = fstat(usb_fd).context("error: could not stat TERMUX_USB_FD")?;
-    let dev_path_link = format!("/sys/dev/char/{}:{}", major(st.st_rdev), minor(st.st_rdev));
+/// Opens the device given by `args`, reading strings in `--language`.
+fn open_device(cli: &Cli, args: &DeviceArgs) -> anyhow::Result<TermuxUsbDevice> {
+    let usb_fd = resolve_usb_fd(cli, args)?;
+    let dev_path = args.device.clone().filter(|_| !args.broker);
+    TermuxUsbDevice::open(usb_fd, dev_path, &cli.language)
+}
 
-    let dev_path = PathBuf::from(readlink(&PathBuf::from(&dev_path_link)).context(format!(
-        "error: could not resolve symlink {}",
-        &dev_path_link
-    ))?);
+fn list(cli: &Cli, args: &FilterArgs) -> anyhow::Result<()> {
+    let usb_dev_list = matching_devices(cli, backend(cli)?.as_ref(), args)?;
+    if cli.json {
+        return print_json(&usb_dev_list);
+    }
+    for dev in &usb_dev_list {
+        println!("{}", dev);
+    }
+    Ok(())
+}
 
-    let mut dev_serial_path = PathBuf::from("/sys/bus/usb/devices");
+fn info(cli: &Cli, args: &DeviceArgs) -> anyhow::Result<()> {
+    let device = open_device(cli, args)?;
+    let usb_info = device.info();
+    if cli.json {
+        return print_json(&usb_info);
+    }
+    print!("{}", usb_info);
+    Ok(())
+}
 
-    dev_serial_path.push(
-        dev_path
-            .file_name()
-            .context("error: could not get device path")?,
-    );
-    dev_serial_path.push("serial");
+fn serial(cli: &Cli, args: &DeviceArgs) -> anyhow::Result<()> {
+    let usb_serial = open_device(cli, args)?.serial()?;
+    if cli.json {
+        return print_json(&usb_serial);
+    }
+    print!("{}", usb_serial);
+    Ok(())
+}
 
-    info!("device serial path: {}", dev_serial_path.display());
+fn print_descriptors<T: Serialize + fmt::Display>(
+    cli: &Cli,
+    descriptors: &T,
+) -> anyhow::Result<()> {
+    if cli.json {
+        return print_json(descriptors);
+    }
+    print!("{}", descriptors);
+    Ok(())
+}
 
-    Ok(UsbSerial {
-        number: serial_number,
-        path: dev_serial_path,
-    })
+fn descriptors(cli: &Cli, args: &DescriptorArgs) -> anyhow::Result<()> {
+    if args.from_sysfs {
+        return descriptors_from_sysfs(cli, &args.device);
+    }
+    let usb_fd = resolve_usb_fd(cli, &args.device)?;
+    #[cfg(feature = "libusb")]
+    if !args.native {
+        let usb_tree = termux_usb::open_device_with_fd(usb_fd)
+            .and_then(|usb_handle| termux_usb::read_descriptor_tree(&usb_handle, &cli.language));
+        match usb_tree {
+            Ok(usb_tree) => return print_descriptors(cli, &usb_tree),
+            Err(e) => log::warn!("libusb failed, decoding raw descriptors: {:#}", e),
+        }
+    }
+    print_descriptors(cli, &read_descriptors_from_fd(usb_fd)?)
 }
 
-pub const fn major(dev: u64) -> u64 {
-    ((dev >> 32) & 0xffff_f000) | ((dev >> 8) & 0x0000_0fff)
+/// Shows the descriptors sysfs has for a listed device without asking for
+/// permission.
+fn descriptors_from_sysfs(cli: &Cli, args: &DeviceArgs) -> anyhow::Result<()> {
+    let sysfs = Sysfs::new(&cli.sysfs_root);
+    let device = match &args.device {
+        Some(dev) => sysfs.device(dev),
+        None => matching_devices(cli, backend(cli)?.as_ref(), &args.filter)?
+            .into_iter()
+            .next()
+            .context("no device given and no listed device matches the filter")?,
+    };
+    let dir = device.sysfs_path.with_context(|| {
+        format!(
+            "no sysfs entry for {} ({})",
+            device.dev_path,
+            device.unreadable.join("; ")
+        )
+    })?;
+    print_descriptors(cli, &read_descriptors_from_sysfs(&dir)?)
 }
 
-pub const fn minor(dev: u64) -> u64 {
-    ((dev >> 12) & 0xffff_ff00) | ((dev) & 0x0000_00ff)
+fn resolve(cli: &Cli, args: &DeviceArgs) -> anyhow::Result<()> {
+    let usb_fd = resolve_usb_fd(cli, args)?;
+    let dev = args
+        .device
+        .clone()
+        .filter(|_| !args.broker)
+        .or_else(|| env::var(TERMUX_USB_DEV).ok());
+    let node = resolve_node(usb_fd, dev.as_deref(), &Sysfs::new(&cli.sysfs_root));
+    if cli.json {
+        return print_json(&node);
+    }
+    print!("{}", node);
+    Ok(())
 }
 
-fn test_usb_with_uds() -> anyhow::Result<()> {
-    let self_path = env::current_exe().context("failed to get executable path")?;
-    let (sock_send, sock_recv) = UnixDatagram::pair().context("could not create socket pair")?;
-    _ = clear_cloexec_flag(&sock_send);
+fn strings(cli: &Cli, args: &DeviceArgs) -> anyhow::Result<()> {
+    let usb_fd = resolve_usb_fd(cli, args)?;
+    let indices = read_descriptors_from_fd(usb_fd)?.string_indices();
+    let usb_handle = open_usb_handle(usb_fd)?;
+    let usb_strings = StringReader::new(usb_handle.as_ref(), &cli.language).dump(&indices);
+    if cli.json {
+        return print_json(&usb_strings);
+    }
+    print!("{}", usb_strings);
+    Ok(())
+}
 
-    let usb_dev_list = get_termux_usb_list();
-    println!("{:?}", usb_dev_list);
+fn control(cli: &Cli, args: &ControlArgs) -> anyhow::Result<()> {
+    let data = match (args.length, &args.data, &args.data_file) {
+        (Some(length), _, _) => ControlData::In(length),
+        (None, Some(data), _) => ControlData::Out(hex::parse_hex_bytes(data).map_err(Error::msg)?),
+        (None, None, Some(path)) if path.as_os_str() == "-" => {
+            let mut payload = vec![];
+            io::stdin()
+                .read_to_end(&mut payload)
+                .context("error reading payload from stdin")?;
+            ControlData::Out(payload)
+        }
+        (None, None, Some(path)) => ControlData::Out(
+            fs::read(path).with_context(|| format!("error reading {}", path.display()))?,
+        ),
+        (None, None, None) => ControlData::Out(vec![]),
+    };
+    let request = ControlRequest::new(
+        args.request_type,
+        args.request,
+        args.value,
+        args.index,
+        data,
+    )?;
+    let device = open_device(cli, &args.device)?;
+    let response = control_transfer(&device, &request, args.transfer_timeout())?;
+    if cli.json {
+        return print_json(&response);
+    }
+    if args.raw {
+        return io::stdout()
+            .write_all(&response.data)
+            .context("error writing to stdout");
+    }
+    print!("{}", response);
+    Ok(())
+}
 
-    for dev in &usb_dev_list {
-        run_under_termux_usb(&dev, &self_path, sock_send.as_raw_fd())
-            .context("error running termux-usb")?;
-
-        let mut buf = vec![0; 256];
-        let mut fds = vec![0; 1];
-        match sock_recv.recv_with_fd(buf.as_mut_slice(), fds.as_mut_slice()) {
-            Ok((_, 0)) => {
-                eprintln!("received message without usb fd");
-            }
-            Ok((size, _)) => {
-                let usb_dev_path = PathBuf::from(String::from_utf8_lossy(&buf[0..size]).as_ref());
-                let usb_fd = fds[0];
-                // use the received info as TERMUX_USB_DEV and TERMUX_USB_FD
-                println!(
-                    "received message (size={}) with fd={}: {}",
-                    size,
-                    usb_fd,
-                    usb_dev_path.display()
+/// Picks the bulk endpoint at `address` or the first one of `interface`
+/// going in `direction`.
+fn bulk_endpoint(
+    device: &TermuxUsbDevice,
+    interface: u8,
+    address: Option<u8>,
+    direction: EndpointDirection,
+) -> anyhow::Result<BulkEndpoint> {
+    let ep = match address {
+        Some(address) => {
+            let ep = device
+                .endpoint(address)
+                .with_context(|| format!("no endpoint {:#04x}", address))?;
+            if ep.direction != direction || ep.transfer_type != TransferType::Bulk {
+                bail!(
+                    "endpoint {:#04x} is {} {}, not bulk {}",
+                    address,
+                    ep.transfer_type,
+                    ep.direction,
+                    direction
                 );
-
-                let usb_serial = init_libusb_device_serial(usb_fd)?;
-                println!("{:?}", usb_serial);
-            }
-            Err(e) => {
-                eprintln!("message receive error: {}", e);
             }
+            ep
         }
-    }
+        None => device
+            .find_endpoint(interface, direction, TransferType::Bulk)
+            .with_context(|| {
+                format!("interface {} has no bulk {} endpoint", interface, direction)
+            })?,
+    };
+    Ok(BulkEndpoint {
+        address: ep.address,
+        max_packet_size: ep.max_packet_size,
+    })
+}
 
+fn usbcat(cli: &Cli, args: &UsbcatArgs) -> anyhow::Result<()> {
+    let mut device = open_device(cli, &args.device)?;
+    let out_ep = (!args.receive_only)
+        .then(|| {
+            bulk_endpoint(
+                &device,
+                args.interface,
+                args.out_endpoint,
+                EndpointDirection::Out,
+            )
+        })
+        .transpose()?;
+    let in_ep = (!args.send_only)
+        .then(|| {
+            bulk_endpoint(
+                &device,
+                args.interface,
+                args.in_endpoint,
+                EndpointDirection::In,
+            )
+        })
+        .transpose()?;
+    device.claim(args.interface, args.detach)?;
+    debug!("streaming through {:?} and {:?}", out_ep, in_ep);
+
+    let counters = termux_usb::usbcat(
+        &device,
+        out_ep,
+        in_ep,
+        &args.options(),
+        io::stdin(),
+        io::stdout(),
+    )?;
+    // stdout carries the data, so the counters go to stderr
+    if cli.json {
+        eprintln!("{}", serde_json::to_string(&counters)?);
+    } else {
+        eprint!("{}", counters);
+    }
     Ok(())
 }
 
-fn run_under_termux_usb(dev: &str, self_path: &PathBuf, sock_fd: RawFd) -> io::Result<ExitStatus> {
-    let mut cmd = Command::new("termux-usb");
-    cmd.arg("-e");
-    cmd.arg(self_path);
-    cmd.args(["-E", "-r", dev]);
-    cmd.env("TERMUX_USB_DEV", dev);
-    cmd.env("TERMUX_ADB_SOCK_FD", sock_fd.to_string());
-    cmd.status()
+/// Set by SIGINT or SIGTERM so the monitor stops and releases the device.
+static STOP: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn request_stop(_: c_int) {
+    STOP.store(true, Ordering::Relaxed);
 }
 
-fn clear_cloexec_flag(socket: &UnixDatagram) -> RawFd {
-    let sock_fd = socket.as_raw_fd();
-    unsafe {
-        let flags = fcntl(sock_fd, F_GETFD);
-        fcntl(sock_fd, F_SETFD, flags & !FD_CLOEXEC);
+/// Makes SIGINT and SIGTERM set [`STOP`] instead of killing the process.
+/// SA_RESTART is left out so a blocking transfer returns early.
+fn stop_on_signals() -> anyhow::Result<()> {
+    let action = SigAction::new(
+        SigHandler::Handler(request_stop),
+        SaFlags::empty(),
+        SigSet::empty(),
+    );
+    for signal in [Signal::SIGINT, Signal::SIGTERM] {
+        // SAFETY: the handler only stores to an atomic
+        unsafe { sigaction(signal, &action) }
+            .with_context(|| format!("failed to handle {}", signal))?;
     }
-    sock_fd
+    Ok(())
 }
 
-fn get_termux_usb_list() -> Vec<String> {
-    if let Ok(out) = Command::new("termux-usb").arg("-l").output() {
-        if let Ok(stdout) = str::from_utf8(&out.stdout) {
-            if let Ok(lst) = serde_json::from_str(stdout) {
-                return lst;
+fn monitor(cli: &Cli, args: &MonitorArgs) -> anyhow::Result<()> {
+    let mut device = open_device(cli, &args.device)?;
+    let (interface, endpoint, max_packet_size) =
+        find_interrupt_endpoint(device.interfaces(), args.interface, args.endpoint)
+            .map(|(interface, ep)| (interface, ep.address, ep.max_packet_size))?;
+    device.claim(interface, !args.no_detach)?;
+    stop_on_signals()?;
+    let options = MonitorOptions {
+        endpoint,
+        buffer_size: args
+            .buffer_size
+            .unwrap_or_else(|| usize::from(max_packet_size)),
+        timeout: args.transfer_timeout(),
+        count: args.count,
+    };
+    debug!("monitoring {:?} on interface {}", options, interface);
+
+    let mut stdout = io::stdout().lock();
+    let result = monitor_interrupts(&device, &options, &STOP, |report| {
+        write_report(&mut stdout, report, cli.json).context("error writing output")
+    });
+    // a closed pipe, e.g. `| head`, ends monitoring like a signal does
+    let seen = match result {
+        Err(e)
+            if e.downcast_ref::<io::Error>().map(io::Error::kind)
+                == Some(io::ErrorKind::BrokenPipe) =>
+        {
+            return Ok(())
+        }
+        r => r?,
+    };
+    eprintln!("{} reports from {:#04x}", seen, endpoint);
+    Ok(())
+}
+
+fn broker(cli: &Cli, args: &FilterArgs) -> anyhow::Result<()> {
+    let json = cli.json;
+    let backend = backend(cli)?;
+    let (sock_send, sock_recv) = UnixDatagram::pair().context("could not create socket pair")?;
+
+    let usb_dev_list: Vec<_> = matching_devices(cli, backend.as_ref(), args)?
+        .into_iter()
+        .map(|dev| dev.dev_path)
+        .collect();
+    if !json {
+        for dev in &usb_dev_list {
+            println!("{}", dev);
+        }
+    }
+
+    let mut results = Vec::with_capacity(usb_dev_list.len());
+    for dev in &usb_dev_list {
+        let mut report = HandoffReport {
+            device: dev.clone(),
+            received: None,
+            info: None,
+            serial: None,
+            error: None,
+        };
+        match backend.request_fd(dev, &sock_send, &sock_recv) {
+            Ok(msg) => {
+                report.received = Some(msg.clone());
+                match msg.fd {
+                    None => {
+                        report.error = Some("received message without usb fd".to_string());
+                        if !json {
+                            eprintln!("received message without usb fd");
+                        }
+                    }
+                    Some(usb_fd) => {
+                        // use the received info as TERMUX_USB_DEV and TERMUX_USB_FD
+                        if !json {
+                            println!(
+                                "received message (size={}) with fd={}: {}",
+                                msg.size,
+                                usb_fd,
+                                msg.dev_path.display()
+                            );
+                        }
+
+                        let dev_path = msg.dev_path.to_str().map(str::to_string);
+                        let inspected = TermuxUsbDevice::open(usb_fd, dev_path, DEFAULT_LANGUAGES)
+                            .and_then(|device| Ok((device.info().clone(), device.serial()?)));
+                        match inspected {
+                            Ok((usb_info, usb_serial)) => {
+                                if !json {
+                                    print!("{}", usb_info);
+                                    print!("{}", usb_serial);
+                                }
+                                report.info = Some(usb_info);
+                                report.serial = Some(usb_serial);
+                            }
+                            Err(e) => {
+                                if !json {
+                                    eprintln!("{:#}", e);
+                                }
+                                report.error = Some(format!("{:#}", e));
+                            }
+                        }
+                    }
+                }
+            }
+            Err(e) => {
+                if !json {
+                    eprintln!("{:#}", e);
+                }
+                report.error = Some(format!("{:#}", e));
             }
         }
+        results.push(report);
     }
-    vec![]
-}
 
-fn test_usb() -> anyhow::Result<()> {
-    let fd_str = env::var("TERMUX_USB_FD").context(concat!(
-        "error: TERMUX_USB_FD not set, ",
-        "you must run termux-usb -e ./termux-usb-test -E -r /dev/bus/usb/..."
-    ))?;
-    let usb_fd = fd_str
-        .parse::<c_int>()
-        .context("error: could not parse TERMUX_USB_FD")?;
-    let usb_serial = init_libusb_device_serial(usb_fd)?;
-    println!("{:?}", usb_serial);
+    if json {
+        print_json(&UdsReport {
+            devices: usb_dev_list,
+            results,
+        })?;
+    }
 
     Ok(())
 }
 
-fn sendfd_to_adb(
-    termux_usb_dev: &str,
-    termux_usb_fd: &str,
-    sock_send_fd: &str,
-) -> anyhow::Result<()> {
-    let socket = unsafe { UnixDatagram::from_raw_fd(sock_send_fd.parse()?) };
-    // send termux_usb_dev and termux_usb_fd to adb-hooks
-    match socket.send_with_fd(termux_usb_dev.as_bytes(), &[termux_usb_fd.parse()?]) {
-        Ok(_) => {
-            info!(
-                "found {}, sending fd {} to parent",
-                &termux_usb_dev, &termux_usb_fd
-            );
-        }
-        Err(e) => {
-            eprintln!("error sending usb fd to parent: {}", e);
+fn serve(cli: &Cli, args: &ServeArgs) -> anyhow::Result<()> {
+    let backend = backend(cli)?;
+    let devices = if args.all || !args.filter.filter().is_empty() {
+        matching_devices(cli, backend.as_ref(), &args.filter)?
+            .into_iter()
+            .map(|dev| dev.dev_path)
+            .collect()
+    } else {
+        args.devices.clone()
+    };
+
+    let mut broker = Broker::new(backend, Sysfs::new(&cli.sysfs_root));
+    for dev in &devices {
+        if let Err(e) = broker.acquire(dev) {
+            eprintln!("{:#}", e);
         }
     }
-    Ok(())
+
+    let listener = bind_broker_socket(&args.socket)
+        .with_context(|| format!("could not bind broker socket {}", args.socket))?;
+    eprintln!(
+        "serving {} device(s) on {}",
+        broker.devices().len(),
+        args.socket
+    );
+    broker.serve(&listener)
 }
 
-fn main() -> anyhow::Result<()> {
-    env_logger::init();
+fn child_send() -> anyhow::Result<()> {
+    let var = |name: &str| {
+        env::var(name).with_context(|| {
+            format!(
+                "{} not set; child-send must be run by termux-usb -e ... -E \
+                 as started from `broker`",
+                name
+            )
+        })
+    };
+    sendfd_to_adb(
+        &var(TERMUX_USB_DEV)?,
+        &var(TERMUX_USB_FD)?,
+        &var(TERMUX_ADB_SOCK_FD)?,
+    )
+}
 
-    match (
-        env::var("TERMUX_USB_DEV"),
-        env::var("TERMUX_USB_FD"),
-        env::var("TERMUX_ADB_SOCK_FD"),
-    ) {
-        (Ok(termux_usb_dev), Ok(termux_usb_fd), Ok(sock_send_fd)) => {
-            return sendfd_to_adb(&termux_usb_dev, &termux_usb_fd, &sock_send_fd);
-        }
-        _ => {}
+/// Default action when no subcommand is given: behave as a termux-usb
+/// callback if started as one.
+fn run_as_callback(json: bool) -> anyhow::Result<()> {
+    if env::var_os(TERMUX_ADB_SOCK_FD).is_some() {
+        return child_send();
+    }
+    if env::var_os(TERMUX_USB_FD).is_none() {
+        Cli::command().print_help()?;
+        bail!(
+            "no subcommand given and {} is not set; \
+             either pick a subcommand or run under termux-usb -e ... -E",
+            TERMUX_USB_FD
+        );
     }
 
-    let args: Vec<String> = std::env::args().collect();
+    let device = TermuxUsbDevice::from_env()?;
+    let usb_tree = device.descriptors();
+    let usb_serial = device.serial()?;
 
-    if args.len() > 1 && args[1] == "--test-uds" {
-        return test_usb_with_uds();
+    if json {
+        return print_json(&DeviceReport {
+            descriptors: usb_tree,
+            serial: usb_serial,
+        });
     }
 
-    test_usb()
+    print!("{}", usb_tree);
+    print!("{}", usb_serial);
+
+    Ok(())
+}
+
+fn run(cli: &Cli) -> anyhow::Result<()> {
+    match &cli.command {
+        None => run_as_callback(cli.json),
+        Some(Command::List(args)) => list(cli, args),
+        Some(Command::Info(args)) => info(cli, args),
+        Some(Command::Serial(args)) => serial(cli, args),
+        Some(Command::Descriptors(args)) => descriptors(cli, args),
+        Some(Command::Strings(args)) => strings(cli, args),
+        Some(Command::Resolve(args)) => resolve(cli, args),
+        Some(Command::Control(args)) => control(cli, args),
+        Some(Command::Usbcat(args)) => usbcat(cli, args),
+        Some(Command::Monitor(args)) => monitor(cli, args),
+        Some(Command::Broker(args)) => broker(cli, args),
+        Some(Command::Serve(args)) => serve(cli, args),
+        Some(Command::ChildSend) => child_send(),
+    }
+}
+
+fn main() -> ExitCode {
+    env_logger::init();
+
+    let cli = Cli::parse();
+
+    match run(&cli) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("Error: {:#}", e);
+            // permission denied, device gone, etc. get distinct exit codes
+            let code = e
+                .downcast_ref::<HandoffError>()
+                .map_or(1, HandoffError::exit_code);
+            ExitCode::from(code)
+        }
+    }
 }
@@ -0,0 +1,274 @@
+//! Watching interrupt IN endpoints, e.g. HID input reports.
+
+use anyhow::{bail, Context};
+use serde::Serialize;
+use std::{
+    fmt,
+    io::{self, Write},
+    sync::atomic::{AtomicBool, Ordering},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use crate::{
+    descriptors::{EndpointDirection, TransferType},
+    handle::UsbDeviceHandle,
+    hex,
+    rawdesc::{EndpointDescriptor, InterfaceDescriptor},
+};
+
+/// What to watch and for how long.
+#[derive(Debug, Clone)]
+pub struct MonitorOptions {
+    pub endpoint: u8,
+    /// Bytes requested per transfer, normally `wMaxPacketSize`.
+    pub buffer_size: usize,
+    /// How long each transfer waits; also bounds how quickly a stop request
+    /// is noticed. Zero waits forever.
+    pub timeout: Duration,
+    /// Stop after this many reports.
+    pub count: Option<u64>,
+}
+
+/// One completed interrupt transfer.
+#[derive(Debug, Clone, Serialize)]
+pub struct InterruptReport {
+    /// Seconds since the Unix epoch.
+    pub time: f64,
+    /// Seconds since monitoring started.
+    pub elapsed: f64,
+    pub endpoint: u8,
+    /// Number of the report, counting from 0.
+    pub sequence: u64,
+    #[serde(serialize_with = "hex::serialize")]
+    pub data: Vec<u8>,
+}
+
+/// Picks the endpoint at `address`, which must be interrupt IN, or else the
+/// first interrupt IN endpoint of `interface` (or of any interface).
+/// Returns it with the number of the interface it belongs to.
+pub fn find_interrupt_endpoint<'a>(
+    interfaces: impl IntoIterator<Item = &'a InterfaceDescriptor>,
+    interface: Option<u8>,
+    address: Option<u8>,
+) -> anyhow::Result<(u8, &'a EndpointDescriptor)> {
+    let is_interrupt_in = |ep: &EndpointDescriptor| {
+        ep.direction == EndpointDirection::In && ep.transfer_type == TransferType::Interrupt
+    };
+    let found = interfaces
+        .into_iter()
+        .filter(|i| interface.is_none_or(|n| i.interface_number == n))
+        .flat_map(|i| i.endpoints.iter().map(move |ep| (i.interface_number, ep)))
+        .find(|(_, ep)| match address {
+            Some(address) => ep.address == address,
+            None => is_interrupt_in(ep),
+        });
+    let (number, ep) = match (found, address, interface) {
+        (Some(found), _, _) => found,
+        (None, Some(address), Some(interface)) => {
+            bail!("interface {} has no endpoint {:#04x}", interface, address)
+        }
+        (None, Some(address), None) => bail!("no endpoint {:#04x}", address),
+        (None, None, Some(interface)) => {
+            bail!("interface {} has no interrupt IN endpoint", interface)
+        }
+        (None, None, None) => bail!("no interrupt IN endpoint found"),
+    };
+    if !is_interrupt_in(ep) {
+        bail!(
+            "endpoint {:#04x} is {} {}, not interrupt in",
+            ep.address,
+            ep.transfer_type,
+            ep.direction
+        );
+    }
+    Ok((number, ep))
+}
+
+/// Submits interrupt transfers on `options.endpoint` until `stop` is set,
+/// `options.count` reports were seen or a transfer fails, handing each report
+/// to `on_report`. Timeouts are not errors. Returns the number of reports.
+pub fn monitor_interrupts<H, F>(
+    handle: &H,
+    options: &MonitorOptions,
+    stop: &AtomicBool,
+    mut on_report: F,
+) -> anyhow::Result<u64>
+where
+    H: UsbDeviceHandle + ?Sized,
+    F: FnMut(&InterruptReport) -> anyhow::Result<()>,
+{
+    let start = Instant::now();
+    let mut buf = vec![0; options.buffer_size.max(1)];
+    let mut sequence = 0;
+    while !stop.load(Ordering::Relaxed) && options.count.is_none_or(|count| sequence < count) {
+        let n = match handle.read_interrupt(options.endpoint, &mut buf, options.timeout) {
+            Ok(n) => n,
+            Err(e)
+                if matches!(
+                    e.kind(),
+                    io::ErrorKind::TimedOut | io::ErrorKind::Interrupted
+                ) =>
+            {
+                continue
+            }
+            Err(e) => {
+                return Err(e).with_context(|| {
+                    format!("interrupt transfer from {:#04x} failed", options.endpoint)
+                })
+            }
+        };
+        let time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        on_report(&InterruptReport {
+            time: time.as_secs_f64(),
+            elapsed: start.elapsed().as_secs_f64(),
+            endpoint: options.endpoint,
+            sequence,
+            data: buf[..n].to_vec(),
+        })?;
+        sequence += 1;
+    }
+    Ok(sequence)
+}
+
+/// Writes `report` as its hexdump or, with `json`, as one line of JSON and
+/// flushes, so a closed pipe shows up as [`io::ErrorKind::BrokenPipe`].
+pub fn write_report<W: Write>(mut out: W, report: &InterruptReport, json: bool) -> io::Result<()> {
+    if json {
+        writeln!(out, "{}", serde_json::to_string(report)?)?;
+    } else {
+        write!(out, "{}", report)?;
+    }
+    out.flush()
+}
+
+impl fmt::Display for InterruptReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "[{:12.6}] {:#04x} #{} {} bytes",
+            self.elapsed,
+            self.endpoint,
+            self.sequence,
+            self.data.len()
+        )?;
+        f.write_str(&hex::hexdump(&self.data))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::handle::testing::Loopback;
+
+    #[test]
+    fn reports_until_count_skipping_timeouts() {
+        let device = Loopback::default();
+        device.push(&[1, 2, 3]);
+        device.push(&[4; 10]);
+        let options = MonitorOptions {
+            endpoint: 0x81,
+            buffer_size: 8,
+            timeout: Duration::from_millis(1),
+            count: Some(3),
+        };
+        let stop = AtomicBool::new(false);
+        let mut reports = vec![];
+        let seen = std::thread::scope(|s| {
+            // the last report only arrives after a few timeouts
+            s.spawn(|| {
+                std::thread::sleep(Duration::from_millis(20));
+                device.push(&[]);
+            });
+            monitor_interrupts(&device, &options, &stop, |r| {
+                reports.push(r.clone());
+                Ok(())
+            })
+        });
+        assert_eq!(seen.unwrap(), 3);
+
+        let data: Vec<_> = reports.iter().map(|r| r.data.clone()).collect();
+        // the 10 byte packet is cut to the buffer size
+        assert_eq!(data, [vec![1, 2, 3], vec![4; 8], vec![]]);
+        assert_eq!(reports[2].sequence, 2);
+        assert!(reports[2].to_string().contains("0x81 #2 0 bytes"));
+    }
+
+    #[test]
+    fn writes_json_lines_and_keeps_broken_pipe() {
+        let report = InterruptReport {
+            time: 1.5,
+            elapsed: 0.25,
+            endpoint: 0x81,
+            sequence: 0,
+            data: vec![0xde, 0xad],
+        };
+        let mut out = vec![];
+        write_report(&mut out, &report, true).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "{\"time\":1.5,\"elapsed\":0.25,\"endpoint\":129,\"sequence\":0,\"data\":\"dead\"}\n"
+        );
+
+        struct ClosedPipe;
+        impl Write for ClosedPipe {
+            fn write(&mut self, _: &[u8]) -> io::Result<usize> {
+                Err(io::ErrorKind::BrokenPipe.into())
+            }
+            fn flush(&mut self) -> io::Result<()> {
+                Ok(())
+            }
+        }
+        for json in [true, false] {
+            let err = write_report(ClosedPipe, &report, json).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::BrokenPipe);
+        }
+    }
+
+    #[test]
+    fn finds_interrupt_endpoint_after_bulk_ones() {
+        let ep = |address: u8, transfer_type| EndpointDescriptor {
+            address,
+            direction: if address & 0x80 != 0 {
+                EndpointDirection::In
+            } else {
+                EndpointDirection::Out
+            },
+            transfer_type,
+            attributes: 0,
+            max_packet_size: 64,
+            interval: 0,
+            extra: vec![],
+        };
+        let interface = |number, endpoints| InterfaceDescriptor {
+            interface_number: number,
+            setting_number: 0,
+            num_endpoints: 0,
+            class_code: 0,
+            sub_class_code: 0,
+            protocol_code: 0,
+            description_index: 0,
+            endpoints,
+            extra: vec![],
+        };
+        let interfaces = [
+            interface(0, vec![ep(0x01, TransferType::Bulk)]),
+            interface(
+                1,
+                vec![
+                    ep(0x02, TransferType::Bulk),
+                    ep(0x82, TransferType::Bulk),
+                    ep(0x83, TransferType::Interrupt),
+                ],
+            ),
+        ];
+        let (number, found) = find_interrupt_endpoint(&interfaces, None, None).unwrap();
+        assert_eq!((number, found.address), (1, 0x83));
+        let (number, found) = find_interrupt_endpoint(&interfaces, Some(1), None).unwrap();
+        assert_eq!((number, found.address), (1, 0x83));
+        assert!(find_interrupt_endpoint(&interfaces, Some(0), None).is_err());
+        // an explicit address must still be interrupt IN
+        assert!(find_interrupt_endpoint(&interfaces, None, Some(0x82)).is_err());
+    }
+}
@@ -0,0 +1,402 @@
+//! Wire format of messages exchanged over the handoff and broker sockets.
+//!
+//! Every message starts with a fixed header followed by a list of fields:
+//!
+//! ```text
+//! magic "TUSB" | version u8 | type u8 | payload length u16 LE | fields...
+//! field: tag u8 | length u16 LE | value
+//! ```
+//!
+//! Unknown field tags are skipped, so newer senders can add fields without
+//! breaking older receivers. A message whose payload is shorter than its
+//! declared length is reported as truncated instead of being decoded.
+
+use std::{
+    error,
+    ffi::OsStr,
+    fmt,
+    os::unix::ffi::OsStrExt,
+    path::{Path, PathBuf},
+};
+
+pub const MAGIC: [u8; 4] = *b"TUSB";
+pub const VERSION: u8 = 1;
+pub const HEADER_LEN: usize = 8;
+/// Upper bound on the size of an encoded message.
+pub const MAX_MESSAGE_LEN: usize = 4096;
+
+/// Error code for failures without a more specific code.
+pub const ERROR_OTHER: u32 = 1;
+/// Error code of a broker that has no device matching the request.
+pub const ERROR_NO_DEVICE: u32 = 2;
+/// The user declined the termux-usb permission dialog.
+pub const ERROR_PERMISSION_DENIED: u32 = 3;
+/// The device disappeared before its fd could be handed over.
+pub const ERROR_DEVICE_GONE: u32 = 4;
+/// The fd given to the termux-usb callback is not usable.
+pub const ERROR_BAD_FD: u32 = 5;
+/// The termux-usb callback exited without sending anything.
+pub const ERROR_CHILD_CRASHED: u32 = 6;
+/// Nothing arrived from the termux-usb callback in time.
+pub const ERROR_TIMEOUT: u32 = 7;
+
+const TAG_DEV_PATH: u8 = 1;
+const TAG_FD_COUNT: u8 = 2;
+const TAG_SERIAL: u8 = 3;
+const TAG_VID_PID: u8 = 4;
+const TAG_ERROR_CODE: u8 = 5;
+const TAG_ERROR_MESSAGE: u8 = 6;
+const TAG_SELECTOR: u8 = 7;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MessageType {
+    /// Device path with its fd attached as ancillary data.
+    DeviceFd = 1,
+    /// Failure to provide a device.
+    Error = 2,
+    /// Client asking a broker for a device.
+    Request = 3,
+}
+
+impl TryFrom<u8> for MessageType {
+    type Error = DecodeError;
+
+    fn try_from(value: u8) -> Result<Self, DecodeError> {
+        match value {
+            1 => Ok(MessageType::DeviceFd),
+            2 => Ok(MessageType::Error),
+            3 => Ok(MessageType::Request),
+            _ => Err(DecodeError::UnknownType(value)),
+        }
+    }
+}
+
+/// Decoded message. Fields not present on the wire are `None`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Message {
+    pub kind: MessageType,
+    pub dev_path: Option<PathBuf>,
+    /// Number of fds sent along with the message.
+    pub fd_count: u8,
+    pub serial: Option<String>,
+    pub vendor_id: Option<u16>,
+    pub product_id: Option<u16>,
+    pub error_code: Option<u32>,
+    pub error_message: Option<String>,
+    /// Device selector of a [`MessageType::Request`].
+    pub selector: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DecodeError {
+    /// Fewer bytes than the header or a field announced.
+    Truncated {
+        expected: usize,
+        actual: usize,
+    },
+    BadMagic([u8; 4]),
+    UnsupportedVersion(u8),
+    UnknownType(u8),
+    /// A known field has a value of the wrong size or encoding.
+    InvalidField(u8),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum EncodeError {
+    /// A single field is longer than its length prefix allows.
+    FieldTooLong(u8),
+    /// The whole message exceeds [`MAX_MESSAGE_LEN`].
+    MessageTooLong(usize),
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DecodeError::Truncated { expected, actual } => write!(
+                f,
+                "truncated message: expected {} bytes, got {}",
+                expected, actual
+            ),
+            DecodeError::BadMagic(magic) => write!(f, "bad magic {:02x?}", magic),
+            DecodeError::UnsupportedVersion(v) => write!(f, "unsupported protocol version {}", v),
+            DecodeError::UnknownType(t) => write!(f, "unknown message type {}", t),
+            DecodeError::InvalidField(tag) => write!(f, "invalid value of field {}", tag),
+        }
+    }
+}
+
+impl error::Error for DecodeError {}
+
+impl fmt::Display for EncodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EncodeError::FieldTooLong(tag) => write!(f, "field {} is too long", tag),
+            EncodeError::MessageTooLong(len) => write!(
+                f,
+                "message of {} bytes exceeds the limit of {}",
+                len, MAX_MESSAGE_LEN
+            ),
+        }
+    }
+}
+
+impl error::Error for EncodeError {}
+
+impl Message {
+    fn new(kind: MessageType) -> Self {
+        Message {
+            kind,
+            dev_path: None,
+            fd_count: 0,
+            serial: None,
+            vendor_id: None,
+            product_id: None,
+            error_code: None,
+            error_message: None,
+            selector: None,
+        }
+    }
+
+    /// Announces `fd_count` fds for the device at `dev_path`.
+    pub fn device_fd(dev_path: &Path, fd_count: u8) -> Self {
+        Message {
+            dev_path: Some(dev_path.to_path_buf()),
+            fd_count,
+            ..Message::new(MessageType::DeviceFd)
+        }
+    }
+
+    /// Reports failure to provide a device.
+    pub fn error(dev_path: Option<&Path>, code: u32, message: &str) -> Self {
+        Message {
+            dev_path: dev_path.map(Path::to_path_buf),
+            error_code: Some(code),
+            error_message: Some(message.to_string()),
+            ..Message::new(MessageType::Error)
+        }
+    }
+
+    /// Asks a broker for the device matching `selector`.
+    pub fn request(selector: &str) -> Self {
+        Message {
+            selector: Some(selector.to_string()),
+            ..Message::new(MessageType::Request)
+        }
+    }
+
+    pub fn encode(&self) -> Result<Vec<u8>, EncodeError> {
+        let mut buf = Vec::with_capacity(64);
+        buf.extend_from_slice(&MAGIC);
+        buf.push(VERSION);
+        buf.push(self.kind as u8);
+        buf.extend_from_slice(&[0, 0]);
+
+        if let Some(path) = &self.dev_path {
+            put_field(&mut buf, TAG_DEV_PATH, path.as_os_str().as_bytes())?;
+        }
+        put_field(&mut buf, TAG_FD_COUNT, &[self.fd_count])?;
+        if let Some(serial) = &self.serial {
+            put_field(&mut buf, TAG_SERIAL, serial.as_bytes())?;
+        }
+        if let (Some(vid), Some(pid)) = (self.vendor_id, self.product_id) {
+            let mut value = [0; 4];
+            value[..2].copy_from_slice(&vid.to_le_bytes());
+            value[2..].copy_from_slice(&pid.to_le_bytes());
+            put_field(&mut buf, TAG_VID_PID, &value)?;
+        }
+        if let Some(code) = self.error_code {
+            put_field(&mut buf, TAG_ERROR_CODE, &code.to_le_bytes())?;
+        }
+        if let Some(message) = &self.error_message {
+            put_field(&mut buf, TAG_ERROR_MESSAGE, message.as_bytes())?;
+        }
+        if let Some(selector) = &self.selector {
+            put_field(&mut buf, TAG_SELECTOR, selector.as_bytes())?;
+        }
+
+        if buf.len() > MAX_MESSAGE_LEN {
+            return Err(EncodeError::MessageTooLong(buf.len()));
+        }
+        let payload_len = (buf.len() - HEADER_LEN) as u16;
+        buf[6..8].copy_from_slice(&payload_len.to_le_bytes());
+        Ok(buf)
+    }
+
+    pub fn decode(buf: &[u8]) -> Result<Self, DecodeError> {
+        if buf.len() < HEADER_LEN {
+            return Err(DecodeError::Truncated {
+                expected: HEADER_LEN,
+                actual: buf.len(),
+            });
+        }
+        let magic = [buf[0], buf[1], buf[2], buf[3]];
+        if magic != MAGIC {
+            return Err(DecodeError::BadMagic(magic));
+        }
+        if buf[4] != VERSION {
+            return Err(DecodeError::UnsupportedVersion(buf[4]));
+        }
+        let mut msg = Message::new(MessageType::try_from(buf[5])?);
+
+        let payload_len = usize::from(u16::from_le_bytes([buf[6], buf[7]]));
+        let expected = HEADER_LEN + payload_len;
+        if buf.len() < expected {
+            return Err(DecodeError::Truncated {
+                expected,
+                actual: buf.len(),
+            });
+        }
+
+        let mut rest = &buf[HEADER_LEN..expected];
+        while !rest.is_empty() {
+            if rest.len() < 3 {
+                return Err(DecodeError::Truncated {
+                    expected: expected + 3 - rest.len(),
+                    actual: expected,
+                });
+            }
+            let tag = rest[0];
+            let len = usize::from(u16::from_le_bytes([rest[1], rest[2]]));
+            if rest.len() < 3 + len {
+                return Err(DecodeError::Truncated {
+                    expected: expected + 3 + len - rest.len(),
+                    actual: expected,
+                });
+            }
+            let value = &rest[3..3 + len];
+            rest = &rest[3 + len..];
+
+            let utf8 = |value: &[u8]| {
+                String::from_utf8(value.to_vec()).map_err(|_| DecodeError::InvalidField(tag))
+            };
+            match tag {
+                TAG_DEV_PATH => msg.dev_path = Some(PathBuf::from(OsStr::from_bytes(value))),
+                TAG_FD_COUNT => match value {
+                    [count] => msg.fd_count = *count,
+                    _ => return Err(DecodeError::InvalidField(tag)),
+                },
+                TAG_SERIAL => msg.serial = Some(utf8(value)?),
+                TAG_VID_PID => match value {
+                    [v0, v1, p0, p1] => {
+                        msg.vendor_id = Some(u16::from_le_bytes([*v0, *v1]));
+                        msg.product_id = Some(u16::from_le_bytes([*p0, *p1]));
+                    }
+                    _ => return Err(DecodeError::InvalidField(tag)),
+                },
+                TAG_ERROR_CODE => match value.try_into() {
+                    Ok(code) => msg.error_code = Some(u32::from_le_bytes(code)),
+                    Err(_) => return Err(DecodeError::InvalidField(tag)),
+                },
+                TAG_ERROR_MESSAGE => msg.error_message = Some(utf8(value)?),
+                TAG_SELECTOR => msg.selector = Some(utf8(value)?),
+                _ => {}
+            }
+        }
+
+        Ok(msg)
+    }
+}
+
+fn put_field(buf: &mut Vec<u8>, tag: u8, value: &[u8]) -> Result<(), EncodeError> {
+    let len = u16::try_from(value.len()).map_err(|_| EncodeError::FieldTooLong(tag))?;
+    buf.push(tag);
+    buf.extend_from_slice(&len.to_le_bytes());
+    buf.extend_from_slice(value);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn device_fd_roundtrip() {
+        let mut msg = Message::device_fd(Path::new("/dev/bus/usb/001/002"), 1);
+        msg.serial = Some("0123456789ABCDEF".to_string());
+        msg.vendor_id = Some(0x18d1);
+        msg.product_id = Some(0x4ee7);
+
+        let buf = msg.encode().unwrap();
+        assert_eq!(&buf[..4], b"TUSB");
+        assert_eq!(Message::decode(&buf).unwrap(), msg);
+    }
+
+    #[test]
+    fn error_and_request_roundtrip() {
+        let err = Message::error(Some(Path::new("/dev/bus/usb/001/003")), 13, "denied");
+        assert_eq!(Message::decode(&err.encode().unwrap()).unwrap(), err);
+
+        let req = Message::request("18d1:4ee7");
+        assert_eq!(Message::decode(&req.encode().unwrap()).unwrap(), req);
+    }
+
+    #[test]
+    fn non_utf8_path_is_preserved() {
+        let path = Path::new(OsStr::from_bytes(b"/dev/bus/usb/\xff\xfe/002"));
+        let msg = Message::device_fd(path, 1);
+        let decoded = Message::decode(&msg.encode().unwrap()).unwrap();
+        assert_eq!(decoded.dev_path.as_deref(), Some(path));
+    }
+
+    #[test]
+    fn truncated_message_is_rejected() {
+        let buf = Message::device_fd(Path::new("/dev/bus/usb/001/002"), 1)
+            .encode()
+            .unwrap();
+        for len in [0, 4, HEADER_LEN, buf.len() - 1] {
+            assert!(
+                matches!(
+                    Message::decode(&buf[..len]),
+                    Err(DecodeError::Truncated { .. })
+                ),
+                "len {}",
+                len
+            );
+        }
+    }
+
+    #[test]
+    fn bad_header_is_rejected() {
+        let mut buf = Message::request("x").encode().unwrap();
+        buf[4] = VERSION + 1;
+        assert_eq!(
+            Message::decode(&buf),
+            Err(DecodeError::UnsupportedVersion(VERSION + 1))
+        );
+        buf[4] = VERSION;
+        buf[5] = 0x7f;
+        assert_eq!(Message::decode(&buf), Err(DecodeError::UnknownType(0x7f)));
+        assert_eq!(
+            Message::decode(b"/dev/bus/usb/001/002"),
+            Err(DecodeError::BadMagic(*b"/dev"))
+        );
+    }
+
+    #[test]
+    fn unknown_fields_are_skipped() {
+        let mut buf = Message::device_fd(Path::new("/dev/bus/usb/001/002"), 1)
+            .encode()
+            .unwrap();
+        buf.extend_from_slice(&[0xee, 2, 0, 0xaa, 0xbb]);
+        let payload_len = (buf.len() - HEADER_LEN) as u16;
+        buf[6..8].copy_from_slice(&payload_len.to_le_bytes());
+
+        let msg = Message::decode(&buf).unwrap();
+        assert_eq!(
+            msg.dev_path.as_deref(),
+            Some(Path::new("/dev/bus/usb/001/002"))
+        );
+        assert_eq!(msg.fd_count, 1);
+    }
+
+    #[test]
+    fn oversized_message_is_rejected() {
+        let msg = Message::request(&"x".repeat(MAX_MESSAGE_LEN));
+        assert_eq!(
+            msg.encode(),
+            Err(EncodeError::MessageTooLong(
+                MAX_MESSAGE_LEN + HEADER_LEN + 3 + 4
+            ))
+        );
+    }
+}
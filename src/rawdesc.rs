@@ -0,0 +1,656 @@
+//! Descriptors decoded from raw bytes, without libusb.
+//!
+//! Reading a usbfs node from offset 0 returns the device descriptor followed
+//! by every configuration descriptor with its interfaces, endpoints and
+//! class-specific descriptors. The sysfs `descriptors` attribute has the same
+//! layout and can be read before permission is granted.
+
+use anyhow::Context;
+use nix::sys::uio::pread;
+use serde::Serialize;
+use std::{error, fmt, fs, os::fd::RawFd, path::Path};
+
+use crate::{
+    descriptors::{EndpointDirection, TransferType},
+    info::fmt_bcd,
+};
+
+const DT_DEVICE: u8 = 0x01;
+const DT_CONFIG: u8 = 0x02;
+const DT_INTERFACE: u8 = 0x04;
+const DT_ENDPOINT: u8 = 0x05;
+const DT_INTERFACE_ASSOCIATION: u8 = 0x0b;
+const DT_HID: u8 = 0x21;
+const DT_CS_INTERFACE: u8 = 0x24;
+const DT_CS_ENDPOINT: u8 = 0x25;
+const DT_SS_ENDPOINT_COMPANION: u8 = 0x30;
+
+const DEVICE_DESCRIPTOR_LEN: usize = 18;
+const CONFIG_DESCRIPTOR_LEN: usize = 9;
+/// usbfs never returns more than this.
+const MAX_DESCRIPTORS_LEN: usize = 64 * 1024;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct DeviceDescriptor {
+    pub usb_version: u16,
+    pub class_code: u8,
+    pub sub_class_code: u8,
+    pub protocol_code: u8,
+    pub max_packet_size: u8,
+    pub vendor_id: u16,
+    pub product_id: u16,
+    pub device_version: u16,
+    pub manufacturer_index: u8,
+    pub product_index: u8,
+    pub serial_number_index: u8,
+    pub num_configurations: u8,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ConfigDescriptor {
+    pub total_length: u16,
+    pub num_interfaces: u8,
+    /// `bConfigurationValue`
+    pub number: u8,
+    pub description_index: u8,
+    pub attributes: u8,
+    pub max_power_ma: u16,
+    pub associations: Vec<InterfaceAssociation>,
+    pub interfaces: Vec<InterfaceDescriptor>,
+    /// Descriptors between the configuration and its first interface.
+    pub extra: Vec<ClassDescriptor>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct InterfaceAssociation {
+    pub first_interface: u8,
+    pub interface_count: u8,
+    pub class_code: u8,
+    pub sub_class_code: u8,
+    pub protocol_code: u8,
+    pub function_index: u8,
+}
+
+/// One alternate setting of an interface.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct InterfaceDescriptor {
+    pub interface_number: u8,
+    pub setting_number: u8,
+    pub num_endpoints: u8,
+    pub class_code: u8,
+    pub sub_class_code: u8,
+    pub protocol_code: u8,
+    pub description_index: u8,
+    pub endpoints: Vec<EndpointDescriptor>,
+    pub extra: Vec<ClassDescriptor>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct EndpointDescriptor {
+    pub address: u8,
+    pub direction: EndpointDirection,
+    pub transfer_type: TransferType,
+    pub attributes: u8,
+    pub max_packet_size: u16,
+    pub interval: u8,
+    pub extra: Vec<ClassDescriptor>,
+}
+
+/// Descriptors that belong to a class or USB 3 rather than the core tree.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum ClassDescriptor {
+    Hid {
+        hid_version: u16,
+        country_code: u8,
+        /// Class descriptors that follow, e.g. the report descriptor.
+        descriptors: Vec<(u8, u16)>,
+    },
+    /// `CS_INTERFACE` or `CS_ENDPOINT`, e.g. CDC or audio functional
+    /// descriptors.
+    Functional {
+        descriptor_type: u8,
+        sub_type: u8,
+        data: Vec<u8>,
+    },
+    SsEndpointCompanion {
+        max_burst: u8,
+        attributes: u8,
+        bytes_per_interval: u16,
+    },
+    Other {
+        descriptor_type: u8,
+        data: Vec<u8>,
+    },
+}
+
+/// Everything read from a usbfs fd or the sysfs `descriptors` attribute.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct RawDescriptors {
+    pub device: DeviceDescriptor,
+    /// As many configurations as were present, in order.
+    pub configurations: Vec<ConfigDescriptor>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ParseError {
+    /// A descriptor runs past the end of the data.
+    Truncated { offset: usize, needed: usize },
+    /// `bLength` is too small for the descriptor type.
+    BadLength { offset: usize, length: u8 },
+    /// A different descriptor type than required at this point.
+    UnexpectedType {
+        offset: usize,
+        expected: u8,
+        actual: u8,
+    },
+}
+
+impl RawDescriptors {
+    /// String indices the descriptors refer to, sorted and without 0.
+    pub fn string_indices(&self) -> Vec<u8> {
+        let device = &self.device;
+        let mut indices = vec![
+            device.manufacturer_index,
+            device.product_index,
+            device.serial_number_index,
+        ];
+        for config in &self.configurations {
+            indices.push(config.description_index);
+            indices.extend(config.associations.iter().map(|a| a.function_index));
+            indices.extend(config.interfaces.iter().map(|i| i.description_index));
+        }
+        indices.retain(|&i| i != 0);
+        indices.sort_unstable();
+        indices.dedup();
+        indices
+    }
+}
+
+impl EndpointDescriptor {
+    /// Endpoint number without the direction bit.
+    pub fn number(&self) -> u8 {
+        self.address & 0x0f
+    }
+}
+
+fn le16(buf: &[u8], i: usize) -> u16 {
+    u16::from_le_bytes([buf[i], buf[i + 1]])
+}
+
+/// Returns the descriptor at `offset` after checking its header.
+fn descriptor_at(buf: &[u8], offset: usize, min_len: usize) -> Result<&[u8], ParseError> {
+    if buf.len() < offset + 2 {
+        return Err(ParseError::Truncated { offset, needed: 2 });
+    }
+    let length = buf[offset];
+    if usize::from(length) < min_len.max(2) {
+        return Err(ParseError::BadLength { offset, length });
+    }
+    let end = offset + usize::from(length);
+    if buf.len() < end {
+        return Err(ParseError::Truncated {
+            offset,
+            needed: length.into(),
+        });
+    }
+    Ok(&buf[offset..end])
+}
+
+fn expect_type(d: &[u8], offset: usize, expected: u8) -> Result<(), ParseError> {
+    if d[1] != expected {
+        return Err(ParseError::UnexpectedType {
+            offset,
+            expected,
+            actual: d[1],
+        });
+    }
+    Ok(())
+}
+
+fn parse_device(d: &[u8]) -> DeviceDescriptor {
+    DeviceDescriptor {
+        usb_version: le16(d, 2),
+        class_code: d[4],
+        sub_class_code: d[5],
+        protocol_code: d[6],
+        max_packet_size: d[7],
+        vendor_id: le16(d, 8),
+        product_id: le16(d, 10),
+        device_version: le16(d, 12),
+        manufacturer_index: d[14],
+        product_index: d[15],
+        serial_number_index: d[16],
+        num_configurations: d[17],
+    }
+}
+
+fn parse_class_descriptor(d: &[u8]) -> ClassDescriptor {
+    match d[1] {
+        DT_HID if d.len() >= 6 => ClassDescriptor::Hid {
+            hid_version: le16(d, 2),
+            country_code: d[4],
+            descriptors: d[6..]
+                .chunks_exact(3)
+                .take(d[5].into())
+                .map(|c| (c[0], u16::from_le_bytes([c[1], c[2]])))
+                .collect(),
+        },
+        DT_CS_INTERFACE | DT_CS_ENDPOINT if d.len() >= 3 => ClassDescriptor::Functional {
+            descriptor_type: d[1],
+            sub_type: d[2],
+            data: d[3..].to_vec(),
+        },
+        DT_SS_ENDPOINT_COMPANION if d.len() >= 6 => ClassDescriptor::SsEndpointCompanion {
+            max_burst: d[2],
+            attributes: d[3],
+            bytes_per_interval: le16(d, 4),
+        },
+        descriptor_type => ClassDescriptor::Other {
+            descriptor_type,
+            data: d[2..].to_vec(),
+        },
+    }
+}
+
+/// Parses one configuration with everything it contains. `superspeed`
+/// selects the 8 mA unit of `bMaxPower`.
+fn parse_config(
+    buf: &[u8],
+    start: usize,
+    superspeed: bool,
+) -> Result<ConfigDescriptor, ParseError> {
+    let d = descriptor_at(buf, start, CONFIG_DESCRIPTOR_LEN)?;
+    expect_type(d, start, DT_CONFIG)?;
+    let total_length = le16(d, 2);
+    let end = start + usize::from(total_length).max(d.len());
+    if buf.len() < end {
+        return Err(ParseError::Truncated {
+            offset: start,
+            needed: total_length.into(),
+        });
+    }
+    let mut config = ConfigDescriptor {
+        total_length,
+        num_interfaces: d[4],
+        number: d[5],
+        description_index: d[6],
+        attributes: d[7],
+        max_power_ma: u16::from(d[8]) * if superspeed { 8 } else { 2 },
+        associations: vec![],
+        interfaces: vec![],
+        extra: vec![],
+    };
+
+    // descriptors must not run past wTotalLength
+    let buf = &buf[..end];
+    let mut offset = start + d.len();
+    while offset < end {
+        let d = descriptor_at(buf, offset, 2)?;
+        match d[1] {
+            DT_INTERFACE => {
+                let d = descriptor_at(buf, offset, 9)?;
+                config.interfaces.push(InterfaceDescriptor {
+                    interface_number: d[2],
+                    setting_number: d[3],
+                    num_endpoints: d[4],
+                    class_code: d[5],
+                    sub_class_code: d[6],
+                    protocol_code: d[7],
+                    description_index: d[8],
+                    endpoints: vec![],
+                    extra: vec![],
+                });
+            }
+            DT_ENDPOINT => {
+                let d = descriptor_at(buf, offset, 7)?;
+                let endpoint = EndpointDescriptor {
+                    address: d[2],
+                    direction: if d[2] & 0x80 != 0 {
+                        EndpointDirection::In
+                    } else {
+                        EndpointDirection::Out
+                    },
+                    transfer_type: match d[3] & 0x03 {
+                        0 => TransferType::Control,
+                        1 => TransferType::Isochronous,
+                        2 => TransferType::Bulk,
+                        _ => TransferType::Interrupt,
+                    },
+                    attributes: d[3],
+                    max_packet_size: le16(d, 4),
+                    interval: d[6],
+                    extra: vec![],
+                };
+                match config.interfaces.last_mut() {
+                    Some(interface) => interface.endpoints.push(endpoint),
+                    // an endpoint outside any interface is malformed, keep it raw
+                    None => config.extra.push(parse_class_descriptor(d)),
+                }
+            }
+            DT_INTERFACE_ASSOCIATION => {
+                let d = descriptor_at(buf, offset, 8)?;
+                config.associations.push(InterfaceAssociation {
+                    first_interface: d[2],
+                    interface_count: d[3],
+                    class_code: d[4],
+                    sub_class_code: d[5],
+                    protocol_code: d[6],
+                    function_index: d[7],
+                });
+            }
+            _ => {
+                let class = parse_class_descriptor(d);
+                // attach to the innermost descriptor seen so far
+                let interface = config.interfaces.last_mut();
+                match interface {
+                    Some(i) => match i.endpoints.last_mut() {
+                        Some(ep) if d[1] != DT_CS_INTERFACE => ep.extra.push(class),
+                        _ => i.extra.push(class),
+                    },
+                    None => config.extra.push(class),
+                }
+            }
+        }
+        offset += d.len();
+    }
+    Ok(config)
+}
+
+/// Parses a device descriptor followed by its configurations.
+///
+/// Configurations missing at the end of `buf` are left out rather than
+/// treated as an error, since sysfs may only have the active one.
+pub fn parse_descriptors(buf: &[u8]) -> Result<RawDescriptors, ParseError> {
+    let d = descriptor_at(buf, 0, DEVICE_DESCRIPTOR_LEN)?;
+    expect_type(d, 0, DT_DEVICE)?;
+    let device = parse_device(d);
+    let superspeed = device.usb_version >= 0x0300;
+
+    let mut configurations = vec![];
+    let mut offset = d.len();
+    while configurations.len() < device.num_configurations.into() && offset < buf.len() {
+        let config = parse_config(buf, offset, superspeed)?;
+        offset += usize::from(config.total_length).max(CONFIG_DESCRIPTOR_LEN);
+        configurations.push(config);
+    }
+    Ok(RawDescriptors {
+        device,
+        configurations,
+    })
+}
+
+/// Reads and parses the descriptors cached by usbfs for `usb_fd`.
+pub fn read_descriptors_from_fd(usb_fd: RawFd) -> anyhow::Result<RawDescriptors> {
+    let mut buf = vec![0u8; MAX_DESCRIPTORS_LEN];
+    let mut len = 0;
+    while len < buf.len() {
+        let n = pread(usb_fd, &mut buf[len..], len as i64)
+            .with_context(|| format!("error reading descriptors from fd {}", usb_fd))?;
+        if n == 0 {
+            break;
+        }
+        len += n;
+    }
+    parse_descriptors(&buf[..len])
+        .with_context(|| format!("error parsing descriptors read from fd {}", usb_fd))
+}
+
+/// Reads and parses the `descriptors` attribute of a sysfs device directory.
+pub fn read_descriptors_from_sysfs(dir: &Path) -> anyhow::Result<RawDescriptors> {
+    let path = dir.join("descriptors");
+    let buf = fs::read(&path).with_context(|| format!("error reading {}", path.display()))?;
+    parse_descriptors(&buf).with_context(|| format!("error parsing {}", path.display()))
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParseError::Truncated { offset, needed } => write!(
+                f,
+                "descriptor at offset {} needs {} bytes, data ends before",
+                offset, needed
+            ),
+            ParseError::BadLength { offset, length } => {
+                write!(f, "invalid bLength {} at offset {}", length, offset)
+            }
+            ParseError::UnexpectedType {
+                offset,
+                expected,
+                actual,
+            } => write!(
+                f,
+                "expected descriptor type {:#04x} at offset {}, found {:#04x}",
+                expected, offset, actual
+            ),
+        }
+    }
+}
+
+impl error::Error for ParseError {}
+
+fn fmt_class_descriptor(
+    f: &mut fmt::Formatter<'_>,
+    indent: &str,
+    c: &ClassDescriptor,
+) -> fmt::Result {
+    match c {
+        ClassDescriptor::Hid {
+            hid_version,
+            country_code,
+            descriptors,
+        } => {
+            writeln!(f, "{}HID Device Descriptor:", indent)?;
+            writeln!(
+                f,
+                "{}  bcdHID             {:>6}",
+                indent,
+                fmt_bcd(*hid_version)
+            )?;
+            writeln!(f, "{}  bCountryCode       {:>6}", indent, country_code)?;
+            for (descriptor_type, length) in descriptors {
+                writeln!(
+                    f,
+                    "{}  bDescriptorType      0x{:02x}  wDescriptorLength {}",
+                    indent, descriptor_type, length
+                )?;
+            }
+            Ok(())
+        }
+        ClassDescriptor::Functional {
+            descriptor_type,
+            sub_type,
+            data,
+        } => writeln!(
+            f,
+            "{}Class-specific Descriptor 0x{:02x} subtype 0x{:02x}: {:02x?}",
+            indent, descriptor_type, sub_type, data
+        ),
+        ClassDescriptor::SsEndpointCompanion {
+            max_burst,
+            attributes,
+            bytes_per_interval,
+        } => writeln!(
+            f,
+            "{}SuperSpeed Endpoint Companion: bMaxBurst {}, bmAttributes 0x{:02x}, \
+             wBytesPerInterval {}",
+            indent, max_burst, attributes, bytes_per_interval
+        ),
+        ClassDescriptor::Other {
+            descriptor_type,
+            data,
+        } => writeln!(
+            f,
+            "{}Unknown Descriptor 0x{:02x}: {:02x?}",
+            indent, descriptor_type, data
+        ),
+    }
+}
+
+impl fmt::Display for RawDescriptors {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let d = &self.device;
+        writeln!(f, "Device Descriptor:")?;
+        writeln!(f, "  bcdUSB             {:>6}", fmt_bcd(d.usb_version))?;
+        writeln!(f, "  bDeviceClass       {:>6}", d.class_code)?;
+        writeln!(f, "  bDeviceSubClass    {:>6}", d.sub_class_code)?;
+        writeln!(f, "  bDeviceProtocol    {:>6}", d.protocol_code)?;
+        writeln!(f, "  bMaxPacketSize0    {:>6}", d.max_packet_size)?;
+        writeln!(f, "  idVendor           0x{:04x}", d.vendor_id)?;
+        writeln!(f, "  idProduct          0x{:04x}", d.product_id)?;
+        writeln!(f, "  bcdDevice          {:>6}", fmt_bcd(d.device_version))?;
+        writeln!(f, "  iManufacturer      {:>6}", d.manufacturer_index)?;
+        writeln!(f, "  iProduct           {:>6}", d.product_index)?;
+        writeln!(f, "  iSerial            {:>6}", d.serial_number_index)?;
+        writeln!(f, "  bNumConfigurations {:>6}", d.num_configurations)?;
+        for config in &self.configurations {
+            writeln!(f, "  Configuration Descriptor:")?;
+            writeln!(f, "    wTotalLength     0x{:04x}", config.total_length)?;
+            writeln!(f, "    bNumInterfaces     {:>5}", config.num_interfaces)?;
+            writeln!(f, "    bConfigurationValue {:>4}", config.number)?;
+            writeln!(f, "    iConfiguration     {:>5}", config.description_index)?;
+            writeln!(f, "    bmAttributes         0x{:02x}", config.attributes)?;
+            writeln!(f, "    MaxPower          {:>5}mA", config.max_power_ma)?;
+            for c in &config.extra {
+                fmt_class_descriptor(f, "    ", c)?;
+            }
+            for iad in &config.associations {
+                writeln!(f, "    Interface Association:")?;
+                writeln!(f, "      bFirstInterface    {:>5}", iad.first_interface)?;
+                writeln!(f, "      bInterfaceCount    {:>5}", iad.interface_count)?;
+                writeln!(f, "      bFunctionClass     {:>5}", iad.class_code)?;
+                writeln!(f, "      bFunctionSubClass  {:>5}", iad.sub_class_code)?;
+                writeln!(f, "      bFunctionProtocol  {:>5}", iad.protocol_code)?;
+                writeln!(f, "      iFunction          {:>5}", iad.function_index)?;
+            }
+            for alt in &config.interfaces {
+                writeln!(f, "    Interface Descriptor:")?;
+                writeln!(f, "      bInterfaceNumber   {:>5}", alt.interface_number)?;
+                writeln!(f, "      bAlternateSetting  {:>5}", alt.setting_number)?;
+                writeln!(f, "      bNumEndpoints      {:>5}", alt.num_endpoints)?;
+                writeln!(f, "      bInterfaceClass    {:>5}", alt.class_code)?;
+                writeln!(f, "      bInterfaceSubClass {:>5}", alt.sub_class_code)?;
+                writeln!(f, "      bInterfaceProtocol {:>5}", alt.protocol_code)?;
+                writeln!(f, "      iInterface         {:>5}", alt.description_index)?;
+                for c in &alt.extra {
+                    fmt_class_descriptor(f, "      ", c)?;
+                }
+                for ep in &alt.endpoints {
+                    writeln!(f, "      Endpoint Descriptor:")?;
+                    writeln!(
+                        f,
+                        "        bEndpointAddress     0x{:02x}  EP {} {}",
+                        ep.address,
+                        ep.number(),
+                        ep.direction
+                    )?;
+                    writeln!(f, "        Transfer Type        {}", ep.transfer_type)?;
+                    writeln!(
+                        f,
+                        "        wMaxPacketSize  0x{:04x}  {} bytes",
+                        ep.max_packet_size,
+                        ep.max_packet_size & 0x7ff
+                    )?;
+                    writeln!(f, "        bInterval          {:>5}", ep.interval)?;
+                    for c in &ep.extra {
+                        fmt_class_descriptor(f, "        ", c)?;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Composite device with an IAD, a CDC function and a HID interface.
+    fn sample() -> Vec<u8> {
+        let mut buf = vec![
+            18, 0x01, 0x00, 0x02, 0xef, 0x02, 0x01, 64, 0xd1, 0x18, 0xe7, 0x4e, 0x00, 0x01, 1, 2,
+            3, 1,
+        ];
+        let config: &[&[u8]] = &[
+            &[9, 0x02, 0, 0, 2, 1, 0, 0xa0, 250],
+            &[8, 0x0b, 0, 1, 0x02, 0x02, 0x01, 0],
+            &[9, 0x04, 0, 0, 1, 0x02, 0x02, 0x01, 4],
+            &[5, 0x24, 0x00, 0x10, 0x01],
+            &[7, 0x05, 0x81, 0x03, 0x08, 0x00, 16],
+            &[9, 0x04, 1, 0, 1, 0x03, 0x00, 0x00, 0],
+            &[9, 0x21, 0x11, 0x01, 0, 1, 0x22, 0x3f, 0x00],
+            &[7, 0x05, 0x02, 0x02, 0x00, 0x02, 0],
+        ];
+        let total: usize = config.iter().map(|d| d.len()).sum();
+        let mut config: Vec<u8> = config.concat();
+        config[2..4].copy_from_slice(&(total as u16).to_le_bytes());
+        buf.extend(config);
+        buf
+    }
+
+    #[test]
+    fn parses_composite_device() {
+        let parsed = parse_descriptors(&sample()).unwrap();
+        assert_eq!(parsed.device.vendor_id, 0x18d1);
+        assert_eq!(parsed.device.product_id, 0x4ee7);
+        assert_eq!(parsed.configurations.len(), 1);
+
+        let config = &parsed.configurations[0];
+        assert_eq!(config.max_power_ma, 500);
+        assert_eq!(config.associations[0].interface_count, 1);
+        assert_eq!(config.interfaces.len(), 2);
+
+        let cdc = &config.interfaces[0];
+        assert_eq!(
+            cdc.extra,
+            [ClassDescriptor::Functional {
+                descriptor_type: 0x24,
+                sub_type: 0x00,
+                data: vec![0x10, 0x01]
+            }]
+        );
+        assert_eq!(cdc.endpoints[0].transfer_type, TransferType::Interrupt);
+        assert_eq!(cdc.endpoints[0].direction, EndpointDirection::In);
+
+        let hid = &config.interfaces[1];
+        assert_eq!(
+            hid.extra,
+            [ClassDescriptor::Hid {
+                hid_version: 0x0111,
+                country_code: 0,
+                descriptors: vec![(0x22, 63)]
+            }]
+        );
+        assert_eq!(hid.endpoints[0].max_packet_size, 512);
+        assert_eq!(hid.endpoints[0].direction, EndpointDirection::Out);
+        assert_eq!(parsed.string_indices(), [1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn rejects_malformed_data() {
+        let buf = sample();
+        assert!(matches!(
+            parse_descriptors(&buf[..40]),
+            Err(ParseError::Truncated { offset: 18, .. })
+        ));
+
+        let mut bad = buf.clone();
+        bad[18 + 9] = 0;
+        assert!(matches!(
+            parse_descriptors(&bad),
+            Err(ParseError::BadLength { offset: 27, .. })
+        ));
+
+        let mut bad = buf;
+        bad[1] = 0x02;
+        assert!(matches!(
+            parse_descriptors(&bad),
+            Err(ParseError::UnexpectedType { expected: 0x01, .. })
+        ));
+
+        // a device descriptor alone is fine, configurations may be missing
+        let parsed = parse_descriptors(&sample()[..18]).unwrap();
+        assert!(parsed.configurations.is_empty());
+    }
+}
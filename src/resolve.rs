@@ -0,0 +1,281 @@
+//! Finding out which device node an fd belongs to.
+//!
+//! `/sys/dev/char` is SELinux-restricted on several Android builds, so the
+//! bus and device numbers are gathered from whatever works: usbfs ioctls on
+//! the fd, its minor number, `TERMUX_USB_DEV`, the `/proc/self/fd` link and
+//! finally a scan of `/sys/bus/usb/devices`. Every attempt is recorded in the
+//! result.
+
+use nix::{fcntl::readlink, sys::stat::fstat};
+use serde::Serialize;
+use std::{
+    fmt,
+    os::fd::RawFd,
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    device::{major, minor},
+    sysfs::{parse_dev_path, Sysfs},
+    usbfs,
+};
+
+/// Ways of learning about the node behind an fd, in the order tried.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Strategy {
+    /// `USBDEVFS_CONNECTINFO` and `USBDEVFS_GET_SPEED` on the fd.
+    Ioctl,
+    /// Decoding the minor number of the usbfs node.
+    Minor,
+    /// Parsing the `TERMUX_USB_DEV` path.
+    EnvDevPath,
+    /// Reading the `/proc/self/fd/N` link.
+    ProcFd,
+    /// Following `/sys/dev/char/MAJOR:MINOR`.
+    SysDevChar,
+    /// Matching `busnum` and `devnum` under `/sys/bus/usb/devices`.
+    SysfsBusDevnum,
+}
+
+/// What one strategy found out, or why it failed.
+#[derive(Debug, Clone, Serialize)]
+pub struct Attempt {
+    pub strategy: Strategy,
+    pub ok: bool,
+    pub detail: String,
+}
+
+/// Everything known about the node behind an fd.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct ResolvedNode {
+    /// Device node, e.g. `/dev/bus/usb/001/002`.
+    pub dev_path: Option<String>,
+    pub busnum: Option<u8>,
+    pub devnum: Option<u8>,
+    pub speed: Option<String>,
+    /// Directory of the device under `/sys/bus/usb/devices`.
+    pub sysfs_path: Option<PathBuf>,
+    pub attempts: Vec<Attempt>,
+}
+
+impl ResolvedNode {
+    fn record(&mut self, strategy: Strategy, result: Result<String, String>) {
+        let (ok, detail) = match result {
+            Ok(detail) => (true, detail),
+            Err(detail) => (false, detail),
+        };
+        self.attempts.push(Attempt {
+            strategy,
+            ok,
+            detail,
+        });
+    }
+
+    /// Takes bus and device number unless they contradict what is known.
+    fn learn_bus_dev(&mut self, busnum: u8, devnum: u8) -> Result<String, String> {
+        if self.busnum.is_some_and(|b| b != busnum) || self.devnum.is_some_and(|d| d != devnum) {
+            let opt = |v: Option<u8>| v.map_or("?".to_string(), |v| v.to_string());
+            return Err(format!(
+                "bus {} device {} disagrees with bus {} device {}",
+                busnum,
+                devnum,
+                opt(self.busnum),
+                opt(self.devnum)
+            ));
+        }
+        self.busnum = Some(busnum);
+        self.devnum = Some(devnum);
+        Ok(format!("bus {} device {}", busnum, devnum))
+    }
+
+    /// Takes bus and device number from a usbfs path unless they contradict
+    /// what is known.
+    fn learn_dev_path(&mut self, dev_path: &str) -> Result<String, String> {
+        let (busnum, devnum) =
+            parse_dev_path(dev_path).ok_or_else(|| format!("{} is not a usbfs path", dev_path))?;
+        let learned = self
+            .learn_bus_dev(busnum, devnum)
+            .map_err(|e| format!("{}: {}", dev_path, e))?;
+        self.dev_path.get_or_insert_with(|| dev_path.to_string());
+        Ok(learned)
+    }
+
+    /// The strategy that located the sysfs directory, if any.
+    pub fn sysfs_strategy(&self) -> Option<Strategy> {
+        self.attempts
+            .iter()
+            .rev()
+            .find(|a| a.ok && matches!(a.strategy, Strategy::SysDevChar | Strategy::SysfsBusDevnum))
+            .map(|a| a.strategy)
+    }
+}
+
+/// Tries every strategy on `usb_fd`. `dev_env` is the value of
+/// `TERMUX_USB_DEV`, if set.
+pub fn resolve_node(usb_fd: RawFd, dev_env: Option<&str>, sysfs: &Sysfs) -> ResolvedNode {
+    let mut node = ResolvedNode::default();
+
+    let ioctl = usbfs::connect_info(usb_fd).map(|(devnum, slow)| {
+        let speed = usbfs::speed(usb_fd).unwrap_or(if slow { "low" } else { "unknown" });
+        (devnum, speed)
+    });
+    let result = match ioctl {
+        Ok((devnum, speed)) => {
+            node.devnum = u8::try_from(devnum).ok();
+            node.speed = Some(speed.to_string());
+            Ok(format!("device {}, {} speed", devnum, speed))
+        }
+        Err(e) => Err(e.to_string()),
+    };
+    node.record(Strategy::Ioctl, result);
+
+    let result =
+        usbfs_minor(usb_fd).and_then(|(busnum, devnum)| node.learn_bus_dev(busnum, devnum));
+    node.record(Strategy::Minor, result);
+
+    let result = match dev_env {
+        Some(dev) => node.learn_dev_path(dev),
+        None => Err("TERMUX_USB_DEV not set".to_string()),
+    };
+    node.record(Strategy::EnvDevPath, result);
+
+    let result = readlink(&PathBuf::from(format!("/proc/self/fd/{}", usb_fd)))
+        .map_err(|e| e.to_string())
+        .and_then(|target| node.learn_dev_path(&target.to_string_lossy()));
+    node.record(Strategy::ProcFd, result);
+
+    let result = sys_dev_char(usb_fd, sysfs);
+    if let Ok(dir) = &result {
+        node.sysfs_path = Some(dir.clone());
+    }
+    node.record(
+        Strategy::SysDevChar,
+        result.map(|d| d.display().to_string()),
+    );
+
+    if node.sysfs_path.is_none() {
+        let result = match (node.busnum, node.devnum) {
+            (Some(busnum), Some(devnum)) => match sysfs.find_device_dir(busnum, devnum) {
+                Ok(Some(dir)) => {
+                    node.sysfs_path = Some(dir.clone());
+                    Ok(dir.display().to_string())
+                }
+                Ok(None) => Err(format!("no entry for bus {} device {}", busnum, devnum)),
+                Err(e) => Err(e.to_string()),
+            },
+            _ => Err("bus and device number unknown".to_string()),
+        };
+        node.record(Strategy::SysfsBusDevnum, result);
+    }
+    node
+}
+
+/// Decodes bus and device number from the minor number of a usbfs node.
+fn usbfs_minor(usb_fd: RawFd) -> Result<(u8, u8), String> {
+    let st = fstat(usb_fd).map_err(|e| format!("fstat: {}", e))?;
+    let (major, minor) = (major(st.st_rdev), minor(st.st_rdev));
+    if major != usbfs::USB_DEVICE_MAJOR {
+        return Err(format!("{}:{} is not a usbfs node", major, minor));
+    }
+    usbfs::bus_dev_from_minor(minor).ok_or_else(|| format!("minor {} names no valid bus", minor))
+}
+
+/// Follows `/sys/dev/char/MAJOR:MINOR` of `usb_fd` to its sysfs directory.
+fn sys_dev_char(usb_fd: RawFd, sysfs: &Sysfs) -> Result<PathBuf, String> {
+    let st = fstat(usb_fd).map_err(|e| format!("fstat: {}", e))?;
+    let link = sysfs.root.join(format!(
+        "dev/char/{}:{}",
+        major(st.st_rdev),
+        minor(st.st_rdev)
+    ));
+    let target = readlink(&link).map_err(|e| format!("{}: {}", link.display(), e))?;
+    let name = Path::new(&target)
+        .file_name()
+        .ok_or_else(|| format!("{}: no device name", link.display()))?;
+    Ok(sysfs.usb_devices_dir().join(name))
+}
+
+impl fmt::Display for Strategy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.pad(match self {
+            Strategy::Ioctl => "ioctl",
+            Strategy::Minor => "minor",
+            Strategy::EnvDevPath => "env-dev-path",
+            Strategy::ProcFd => "proc-fd",
+            Strategy::SysDevChar => "sys-dev-char",
+            Strategy::SysfsBusDevnum => "sysfs-bus-devnum",
+        })
+    }
+}
+
+impl fmt::Display for ResolvedNode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let opt = |v: Option<String>| v.unwrap_or_else(|| "?".to_string());
+        writeln!(f, "Device node:  {}", opt(self.dev_path.clone()))?;
+        writeln!(
+            f,
+            "Bus/device:   {}/{}",
+            opt(self.busnum.map(|b| b.to_string())),
+            opt(self.devnum.map(|d| d.to_string()))
+        )?;
+        writeln!(f, "Speed:        {}", opt(self.speed.clone()))?;
+        writeln!(
+            f,
+            "Sysfs:        {}",
+            opt(self.sysfs_path.as_ref().map(|p| p.display().to_string()))
+        )?;
+        for a in &self.attempts {
+            let status = if a.ok { "ok" } else { "fail" };
+            writeln!(f, "  {:<4} {:<16} {}", status, a.strategy, a.detail)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{fs, os::fd::AsRawFd, process};
+
+    #[test]
+    fn falls_back_to_bus_devnum_matching() {
+        let root = std::env::temp_dir().join(format!("termux-usb-resolve-{}", process::id()));
+        let dir = root.join("bus/usb/devices/1-1.2");
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("busnum"), "1\n").unwrap();
+        fs::write(dir.join("devnum"), "5\n").unwrap();
+        let file = fs::File::open(dir.join("busnum")).unwrap();
+
+        let node = resolve_node(
+            file.as_raw_fd(),
+            Some("/dev/bus/usb/001/005"),
+            &Sysfs::new(&root),
+        );
+        let ok: Vec<_> = node
+            .attempts
+            .iter()
+            .filter(|a| a.ok)
+            .map(|a| a.strategy)
+            .collect();
+        assert_eq!(ok, [Strategy::EnvDevPath, Strategy::SysfsBusDevnum]);
+        assert_eq!(node.dev_path.as_deref(), Some("/dev/bus/usb/001/005"));
+        assert_eq!(node.sysfs_path, Some(dir));
+        assert_eq!(node.sysfs_strategy(), Some(Strategy::SysfsBusDevnum));
+
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn rejects_dev_path_on_another_bus() {
+        let mut node = ResolvedNode {
+            busnum: Some(2),
+            devnum: Some(5),
+            ..ResolvedNode::default()
+        };
+        assert!(node.learn_dev_path("/dev/bus/usb/001/005").is_err());
+        assert_eq!(node.dev_path, None);
+        assert!(node.learn_dev_path("/dev/bus/usb/002/005").is_ok());
+        assert_eq!(node.dev_path.as_deref(), Some("/dev/bus/usb/002/005"));
+    }
+}
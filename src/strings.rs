@@ -0,0 +1,259 @@
+//! String descriptors in the user's preferred language.
+//!
+//! String descriptor 0 holds the LANGIDs a device supports. Some devices
+//! return an empty or malformed table, so strings are then read raw with the
+//! preferred LANGID (or 0) and decoded from UTF-16LE without libusb's help.
+
+use anyhow::{bail, Context};
+use log::debug;
+use serde::Serialize;
+use std::{fmt, time::Duration};
+
+use crate::handle::UsbDeviceHandle;
+
+/// LANGID of US English.
+pub const LANG_EN_US: u16 = 0x0409;
+/// Language preference used when none is configured.
+pub const DEFAULT_LANGUAGES: &[u16] = &[LANG_EN_US];
+
+const STRING_TIMEOUT: Duration = Duration::from_secs(1);
+const DESCRIPTOR_TYPE_STRING: u8 = 0x03;
+const REQUEST_GET_DESCRIPTOR: u8 = 0x06;
+/// bmRequestType of a standard device-to-host request to the device.
+const REQUEST_TYPE_STANDARD_IN: u8 = 0x80;
+
+/// One string descriptor as read by [`StringReader::dump`].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct UsbString {
+    pub index: u8,
+    pub langid: u16,
+    pub value: String,
+}
+
+/// LANGID table and every string a device returns.
+#[derive(Debug, Clone, Serialize)]
+pub struct UsbStringTable {
+    /// Supported LANGIDs; empty if the table is missing or malformed.
+    pub langids: Vec<u16>,
+    /// LANGID used for single string reads.
+    pub language: u16,
+    pub strings: Vec<UsbString>,
+}
+
+/// Reads string descriptors of one device in a chosen language.
+pub struct StringReader<'a, H: UsbDeviceHandle + ?Sized> {
+    handle: &'a H,
+    /// LANGIDs from string descriptor 0, empty if it could not be read.
+    pub langids: Vec<u16>,
+    /// LANGID strings are read in.
+    pub language: u16,
+}
+
+impl<'a, H: UsbDeviceHandle + ?Sized> StringReader<'a, H> {
+    /// Reads the LANGID table and picks a language by `preference`, which
+    /// is also used as is when the table is unusable.
+    pub fn new(handle: &'a H, preference: &[u16]) -> Self {
+        let langids = match read_langids(handle) {
+            Ok(langids) => langids,
+            Err(e) => {
+                debug!("could not read LANGID table: {:#}", e);
+                vec![]
+            }
+        };
+        let language = pick_language(&langids, preference)
+            .or_else(|| preference.first().copied())
+            .unwrap_or(LANG_EN_US);
+        debug!("reading strings in language {:04x}", language);
+        StringReader {
+            handle,
+            langids,
+            language,
+        }
+    }
+
+    /// Reads string `index` in `langid`.
+    pub fn read_in(&self, index: u8, langid: u16) -> anyhow::Result<String> {
+        let buf = read_string_descriptor(self.handle, index, langid)?;
+        decode_string_descriptor(&buf)
+            .with_context(|| format!("bad string descriptor {} ({:04x})", index, langid))
+    }
+
+    /// Reads the string `index` refers to, logging failures. Without a
+    /// LANGID table, LANGID 0 is tried after the preferred one.
+    pub fn read(&self, index: Option<u8>, name: &str) -> Option<String> {
+        let index = index?;
+        let mut result = self.read_in(index, self.language);
+        if result.is_err() && self.langids.is_empty() {
+            result = self.read_in(index, 0);
+        }
+        match result {
+            Ok(s) => Some(s),
+            Err(e) => {
+                debug!("could not read {} string: {:#}", name, e);
+                None
+            }
+        }
+    }
+
+    /// Reads the strings at `indices` in every supported language, skipping
+    /// indices the device rejects. Probing all 255 indices instead would
+    /// take minutes on devices that time out on unknown ones.
+    pub fn dump(&self, indices: &[u8]) -> UsbStringTable {
+        let languages = if self.langids.is_empty() {
+            vec![self.language]
+        } else {
+            self.langids.clone()
+        };
+        let mut strings = vec![];
+        for &langid in &languages {
+            for &index in indices {
+                match self.read_in(index, langid) {
+                    Ok(value) => strings.push(UsbString {
+                        index,
+                        langid,
+                        value,
+                    }),
+                    Err(e) => debug!("string {} ({:04x}): {:#}", index, langid, e),
+                }
+            }
+        }
+        UsbStringTable {
+            langids: self.langids.clone(),
+            language: self.language,
+            strings,
+        }
+    }
+}
+
+/// Issues GET_DESCRIPTOR for string `index` and returns the raw bytes.
+fn read_string_descriptor<H: UsbDeviceHandle + ?Sized>(
+    handle: &H,
+    index: u8,
+    langid: u16,
+) -> anyhow::Result<Vec<u8>> {
+    let mut buf = [0u8; 255];
+    let len = handle
+        .read_control(
+            REQUEST_TYPE_STANDARD_IN,
+            REQUEST_GET_DESCRIPTOR,
+            u16::from(DESCRIPTOR_TYPE_STRING) << 8 | u16::from(index),
+            langid,
+            &mut buf,
+            STRING_TIMEOUT,
+        )
+        .with_context(|| format!("error reading string descriptor {}", index))?;
+    Ok(buf[..len].to_vec())
+}
+
+/// Reads and parses the LANGID table in string descriptor 0.
+pub fn read_langids<H: UsbDeviceHandle + ?Sized>(handle: &H) -> anyhow::Result<Vec<u16>> {
+    parse_langids(&read_string_descriptor(handle, 0, 0)?)
+}
+
+/// Checks the header of a string descriptor and returns its payload.
+fn string_payload(buf: &[u8]) -> anyhow::Result<&[u8]> {
+    if buf.len() < 2 {
+        bail!("descriptor too short ({} bytes)", buf.len());
+    }
+    if buf[1] != DESCRIPTOR_TYPE_STRING {
+        bail!("not a string descriptor (type {:#04x})", buf[1]);
+    }
+    let len = usize::from(buf[0]).min(buf.len());
+    if len < 2 {
+        bail!("invalid bLength {}", buf[0]);
+    }
+    Ok(&buf[2..len])
+}
+
+/// Parses string descriptor 0 into LANGIDs.
+pub fn parse_langids(buf: &[u8]) -> anyhow::Result<Vec<u16>> {
+    let payload = string_payload(buf)?;
+    if payload.is_empty() || payload.len() % 2 != 0 {
+        bail!("malformed LANGID table of {} bytes", payload.len());
+    }
+    Ok(payload
+        .chunks_exact(2)
+        .map(|c| u16::from_le_bytes([c[0], c[1]]))
+        .collect())
+}
+
+/// Decodes the UTF-16LE payload of a string descriptor. Unpaired
+/// surrogates and a trailing odd byte are replaced rather than rejected.
+pub fn decode_string_descriptor(buf: &[u8]) -> anyhow::Result<String> {
+    let payload = string_payload(buf)?;
+    let units: Vec<u16> = payload
+        .chunks(2)
+        .map(|c| u16::from_le_bytes([c[0], *c.get(1).unwrap_or(&0)]))
+        .collect();
+    Ok(String::from_utf16_lossy(&units)
+        .trim_end_matches('\0')
+        .to_string())
+}
+
+/// Picks the first preferred LANGID the device supports, then one with the
+/// same primary language (e.g. en-GB for en-US), then the device's first.
+pub fn pick_language(available: &[u16], preference: &[u16]) -> Option<u16> {
+    let primary = |langid: u16| langid & 0x03ff;
+    preference
+        .iter()
+        .find(|p| available.contains(p))
+        .or_else(|| {
+            preference
+                .iter()
+                .find_map(|&p| available.iter().find(|&&a| primary(a) == primary(p)))
+        })
+        .or_else(|| available.first())
+        .copied()
+}
+
+impl fmt::Display for UsbStringTable {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let langids: Vec<_> = self.langids.iter().map(|l| format!("{:04x}", l)).collect();
+        if langids.is_empty() {
+            writeln!(f, "LANGIDs: none (read raw as {:04x})", self.language)?;
+        } else {
+            writeln!(f, "LANGIDs: {}", langids.join(" "))?;
+        }
+        for s in &self.strings {
+            writeln!(f, "  {:04x} {:>3} {}", s.langid, s.index, s.value)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_langid_table() {
+        assert_eq!(
+            parse_langids(&[6, 3, 0x09, 0x04, 0x07, 0x04]).unwrap(),
+            [0x0409, 0x0407]
+        );
+        assert!(parse_langids(&[2, 3]).is_err());
+        assert!(parse_langids(&[5, 3, 0x09, 0x04, 0x07]).is_err());
+        assert!(parse_langids(&[4, 2, 0x09, 0x04]).is_err());
+    }
+
+    #[test]
+    fn decodes_utf16le() {
+        let buf = [12, 3, b'P', 0, b'i', 0, b'x', 0, 0xe9, 0, b'l', 0];
+        assert_eq!(decode_string_descriptor(&buf).unwrap(), "Pixél");
+        // bLength longer than the transfer and an odd trailing byte
+        assert_eq!(
+            decode_string_descriptor(&[40, 3, b'A', 0, b'B']).unwrap(),
+            "AB"
+        );
+    }
+
+    #[test]
+    fn picks_preferred_language() {
+        let available = [0x0407, 0x0809];
+        assert_eq!(pick_language(&available, &[0x0407]), Some(0x0407));
+        // en-GB stands in for en-US
+        assert_eq!(pick_language(&available, DEFAULT_LANGUAGES), Some(0x0809));
+        assert_eq!(pick_language(&available, &[0x040c]), Some(0x0407));
+        assert_eq!(pick_language(&[], DEFAULT_LANGUAGES), None);
+    }
+}
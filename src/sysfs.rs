@@ -0,0 +1,365 @@
+//! Device metadata from sysfs, available before permission is granted.
+//!
+//! `termux-usb -l` only reports `/dev/bus/usb/BBB/DDD` paths. The matching
+//! `/sys/bus/usb/devices/*` entry is found by comparing `busnum` and
+//! `devnum`, without opening the device node. Reads blocked by SELinux are
+//! recorded instead of failing the whole lookup.
+
+use log::debug;
+use serde::Serialize;
+use std::{
+    fmt, fs, io,
+    path::{Path, PathBuf},
+};
+
+use crate::identity::DeviceIdentity;
+
+/// Where sysfs is mounted on a real system.
+pub const DEFAULT_SYSFS_ROOT: &str = "/sys";
+
+/// Metadata of one device as exposed by sysfs.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct SysfsDevice {
+    /// Device node, e.g. `/dev/bus/usb/001/002`.
+    pub dev_path: String,
+    /// Matching directory under `/sys/bus/usb/devices`, if found.
+    pub sysfs_path: Option<PathBuf>,
+    pub busnum: Option<u8>,
+    pub devnum: Option<u8>,
+    /// Kernel name of the device, i.e. bus and port chain like `1-1.2`.
+    pub port_path: Option<String>,
+    pub vendor_id: Option<u16>,
+    pub product_id: Option<u16>,
+    pub device_class: Option<u8>,
+    pub device_sub_class: Option<u8>,
+    pub device_protocol: Option<u8>,
+    pub manufacturer: Option<String>,
+    pub product: Option<String>,
+    pub serial_number: Option<String>,
+    /// Link speed named as in [`crate::info::speed_name`].
+    pub speed: Option<String>,
+    /// Interfaces of the active configuration.
+    pub interfaces: Vec<SysfsInterface>,
+    /// Attributes or directories that could not be read, with the reason.
+    pub unreadable: Vec<String>,
+}
+
+/// Class triple of one interface, e.g. `ff:42:01` for adb.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct SysfsInterface {
+    pub class: u8,
+    pub sub_class: u8,
+    pub protocol: u8,
+}
+
+/// Sysfs mounted at `root`; tests point this at a fake tree.
+#[derive(Debug, Clone)]
+pub struct Sysfs {
+    pub root: PathBuf,
+}
+
+impl Default for Sysfs {
+    fn default() -> Self {
+        Sysfs::new(DEFAULT_SYSFS_ROOT)
+    }
+}
+
+/// Extracts bus and device number from `/dev/bus/usb/BBB/DDD`.
+pub fn parse_dev_path(dev_path: &str) -> Option<(u8, u8)> {
+    let mut parts = dev_path.trim_end_matches('/').rsplit('/');
+    let devnum = parts.next()?.parse().ok()?;
+    let busnum = parts.next()?.parse().ok()?;
+    Some((busnum, devnum))
+}
+
+/// Converts the sysfs `speed` attribute (Mbit/s) to a speed name.
+pub fn speed_from_mbps(mbps: &str) -> &'static str {
+    match mbps.trim() {
+        "1.5" => "low",
+        "12" => "full",
+        "480" => "high",
+        "5000" => "super",
+        "10000" | "20000" => "super+",
+        _ => "unknown",
+    }
+}
+
+impl Sysfs {
+    pub fn new<P: AsRef<Path>>(root: P) -> Self {
+        Sysfs {
+            root: root.as_ref().to_path_buf(),
+        }
+    }
+
+    /// `/sys/bus/usb/devices` under this root.
+    pub fn usb_devices_dir(&self) -> PathBuf {
+        self.root.join("bus/usb/devices")
+    }
+
+    /// Finds the sysfs directory of the device with the given numbers.
+    pub fn find_device_dir(&self, busnum: u8, devnum: u8) -> io::Result<Option<PathBuf>> {
+        for entry in fs::read_dir(self.usb_devices_dir())? {
+            let path = entry?.path();
+            let name = path.file_name().unwrap_or_default().to_string_lossy();
+            // interfaces look like 1-1.2:1.0 and have no busnum/devnum
+            if name.contains(':') {
+                continue;
+            }
+            let num = |attr| {
+                read_attr(&path, attr)
+                    .ok()
+                    .and_then(|s| s.parse::<u8>().ok())
+            };
+            if num("busnum") == Some(busnum) && num("devnum") == Some(devnum) {
+                return Ok(Some(path));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Reads the interfaces of device `name` (entries named `name:C.I`).
+    pub fn interfaces(&self, name: &str) -> io::Result<Vec<SysfsInterface>> {
+        let prefix = format!("{}:", name);
+        let mut interfaces = vec![];
+        for entry in fs::read_dir(self.usb_devices_dir())? {
+            let path = entry?.path();
+            let file_name = path.file_name().unwrap_or_default().to_string_lossy();
+            if !file_name.starts_with(&prefix) {
+                continue;
+            }
+            let hex = |attr| {
+                read_attr(&path, attr)
+                    .ok()
+                    .and_then(|s| u8::from_str_radix(&s, 16).ok())
+            };
+            if let (Some(class), Some(sub_class), Some(protocol)) = (
+                hex("bInterfaceClass"),
+                hex("bInterfaceSubClass"),
+                hex("bInterfaceProtocol"),
+            ) {
+                interfaces.push(SysfsInterface {
+                    class,
+                    sub_class,
+                    protocol,
+                });
+            }
+        }
+        Ok(interfaces)
+    }
+
+    /// Collects whatever sysfs exposes about `dev_path` without opening it.
+    pub fn device(&self, dev_path: &str) -> SysfsDevice {
+        let mut device = SysfsDevice {
+            dev_path: dev_path.to_string(),
+            ..Default::default()
+        };
+        let Some((busnum, devnum)) = parse_dev_path(dev_path) else {
+            device
+                .unreadable
+                .push(format!("{}: not a usbfs path", dev_path));
+            return device;
+        };
+        device.busnum = Some(busnum);
+        device.devnum = Some(devnum);
+
+        let dir = match self.find_device_dir(busnum, devnum) {
+            Ok(Some(dir)) => dir,
+            Ok(None) => {
+                device.unreadable.push(format!(
+                    "no sysfs entry for bus {} device {}",
+                    busnum, devnum
+                ));
+                return device;
+            }
+            Err(e) => {
+                debug!("could not scan {}: {}", self.usb_devices_dir().display(), e);
+                device
+                    .unreadable
+                    .push(format!("{}: {}", self.usb_devices_dir().display(), e));
+                return device;
+            }
+        };
+
+        let mut attr = |name: &str| match read_attr(&dir, name) {
+            Ok(value) => Some(value),
+            Err(e) => {
+                // optional string attributes are simply absent on many devices
+                if e.kind() != io::ErrorKind::NotFound {
+                    device.unreadable.push(format!("{}: {}", name, e));
+                }
+                None
+            }
+        };
+        let vendor_id = attr("idVendor").and_then(|s| u16::from_str_radix(&s, 16).ok());
+        let product_id = attr("idProduct").and_then(|s| u16::from_str_radix(&s, 16).ok());
+        let device_class = attr("bDeviceClass").and_then(|s| u8::from_str_radix(&s, 16).ok());
+        let device_sub_class =
+            attr("bDeviceSubClass").and_then(|s| u8::from_str_radix(&s, 16).ok());
+        let device_protocol = attr("bDeviceProtocol").and_then(|s| u8::from_str_radix(&s, 16).ok());
+        let manufacturer = attr("manufacturer");
+        let product = attr("product");
+        let serial_number = attr("serial");
+        let speed = attr("speed").map(|s| speed_from_mbps(&s).to_string());
+
+        device.port_path = dir
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned());
+        if let Some(port_path) = &device.port_path {
+            match self.interfaces(port_path) {
+                Ok(interfaces) => device.interfaces = interfaces,
+                Err(e) => device.unreadable.push(format!("interfaces: {}", e)),
+            }
+        }
+        device.sysfs_path = Some(dir);
+        SysfsDevice {
+            vendor_id,
+            product_id,
+            device_class,
+            device_sub_class,
+            device_protocol,
+            manufacturer,
+            product,
+            serial_number,
+            speed,
+            ..device
+        }
+    }
+}
+
+impl SysfsDevice {
+    /// Identity as it would be computed from the device descriptor, if the
+    /// ids could be read.
+    pub fn identity(&self) -> Option<DeviceIdentity> {
+        Some(DeviceIdentity::new(
+            self.serial_number.clone(),
+            self.vendor_id?,
+            self.product_id?,
+            self.port_path.clone(),
+        ))
+    }
+}
+
+fn read_attr(dir: &Path, name: &str) -> io::Result<String> {
+    fs::read_to_string(dir.join(name)).map(|s| s.trim_end().to_string())
+}
+
+impl fmt::Display for SysfsDevice {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.dev_path)?;
+        if let (Some(vid), Some(pid)) = (self.vendor_id, self.product_id) {
+            write!(f, " {:04x}:{:04x}", vid, pid)?;
+        }
+        for s in [&self.manufacturer, &self.product].into_iter().flatten() {
+            write!(f, " {}", s)?;
+        }
+        let mut details = vec![];
+        if let Some(class) = self.device_class {
+            details.push(format!("class {:02x}", class));
+        }
+        if let Some(speed) = &self.speed {
+            details.push(speed.clone());
+        }
+        if let Some(port) = &self.port_path {
+            details.push(format!("port {}", port));
+        }
+        if !details.is_empty() {
+            write!(f, " ({})", details.join(", "))?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::process;
+
+    fn fake_sysfs(name: &str) -> PathBuf {
+        let root =
+            std::env::temp_dir().join(format!("termux-usb-sysfs-{}-{}", process::id(), name));
+        let devices = root.join("bus/usb/devices");
+        let attrs: &[(&str, &[(&str, &str)])] = &[
+            (
+                "usb1",
+                &[("busnum", "1"), ("devnum", "1"), ("idVendor", "1d6b")],
+            ),
+            (
+                "1-1.2",
+                &[
+                    ("busnum", "1"),
+                    ("devnum", "5"),
+                    ("idVendor", "18d1"),
+                    ("idProduct", "4ee7"),
+                    ("bDeviceClass", "ff"),
+                    ("manufacturer", "Google"),
+                    ("product", "Pixel 5"),
+                    ("speed", "480"),
+                ],
+            ),
+            (
+                "1-1.2:1.0",
+                &[
+                    ("bInterfaceClass", "ff"),
+                    ("bInterfaceSubClass", "42"),
+                    ("bInterfaceProtocol", "01"),
+                ],
+            ),
+        ];
+        for (dir, files) in attrs {
+            fs::create_dir_all(devices.join(dir)).unwrap();
+            for (file, value) in *files {
+                fs::write(devices.join(dir).join(file), format!("{}\n", value)).unwrap();
+            }
+        }
+        root
+    }
+
+    #[test]
+    fn parses_dev_path() {
+        assert_eq!(parse_dev_path("/dev/bus/usb/001/005"), Some((1, 5)));
+        assert_eq!(parse_dev_path("/dev/bus/usb/002/123/"), Some((2, 123)));
+        assert_eq!(parse_dev_path("/dev/null"), None);
+    }
+
+    #[test]
+    fn reads_device_metadata() {
+        let root = fake_sysfs("metadata");
+        let device = Sysfs::new(&root).device("/dev/bus/usb/001/005");
+
+        assert_eq!(device.port_path.as_deref(), Some("1-1.2"));
+        assert_eq!(device.vendor_id, Some(0x18d1));
+        assert_eq!(device.product_id, Some(0x4ee7));
+        assert_eq!(device.device_class, Some(0xff));
+        assert_eq!(device.manufacturer.as_deref(), Some("Google"));
+        assert_eq!(device.product.as_deref(), Some("Pixel 5"));
+        assert_eq!(device.serial_number, None);
+        assert_eq!(device.speed.as_deref(), Some("high"));
+        assert_eq!(
+            device.interfaces,
+            [SysfsInterface {
+                class: 0xff,
+                sub_class: 0x42,
+                protocol: 0x01
+            }]
+        );
+        assert!(device.unreadable.is_empty(), "{:?}", device.unreadable);
+
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn degrades_without_sysfs() {
+        let root = fake_sysfs("missing");
+        let sysfs = Sysfs::new(&root);
+
+        let device = sysfs.device("/dev/bus/usb/001/042");
+        assert_eq!(device.busnum, Some(1));
+        assert_eq!(device.sysfs_path, None);
+        assert_eq!(device.unreadable.len(), 1);
+
+        fs::remove_dir_all(&root).unwrap();
+        let device = sysfs.device("/dev/bus/usb/001/005");
+        assert_eq!(device.vendor_id, None);
+        assert_eq!(device.unreadable.len(), 1);
+    }
+}
@@ -0,0 +1,533 @@
+//! Wrappers around the `termux-usb` command from Termux:API.
+
+use crate::{
+    handoff::{self, HandoffError, ReceivedUsbFd},
+    usbfs,
+};
+use anyhow::Context;
+use libc::c_int;
+use log::{debug, warn};
+use nix::{
+    errno::Errno,
+    poll::{poll, PollFd, PollFlags},
+};
+use serde::{de, Deserialize, Deserializer, Serialize};
+use std::{
+    env, error, fmt,
+    io::{self, Read},
+    os::{
+        fd::{AsRawFd, RawFd},
+        unix::net::UnixDatagram,
+    },
+    path::Path,
+    process::{Child, Command, ExitStatus, Output, Stdio},
+    str, thread,
+    time::{Duration, Instant},
+};
+
+/// Device path set by `termux-usb` for the callback.
+pub const TERMUX_USB_DEV: &str = "TERMUX_USB_DEV";
+/// Granted fd set by `termux-usb -E` for the callback.
+pub const TERMUX_USB_FD: &str = "TERMUX_USB_FD";
+/// Socket fd set by [`run_under_termux_usb`] for the callback.
+pub const TERMUX_ADB_SOCK_FD: &str = "TERMUX_ADB_SOCK_FD";
+
+/// Default time to wait for the user to answer the permission dialog.
+pub const DEFAULT_REQUEST_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// How often the `termux-usb` child is checked while waiting for its fd.
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Why `termux-usb -l` did not produce a device list.
+#[derive(Debug)]
+pub enum ListError {
+    /// `termux-usb` is not on `PATH`.
+    NotInstalled,
+    /// `termux-usb` could not be started for another reason.
+    Spawn(io::Error),
+    /// `termux-usb` exited with an error.
+    Failed {
+        status: ExitStatus,
+        stderr: String,
+    },
+    /// `termux-usb` printed nothing, e.g. because the Termux:API app is
+    /// missing.
+    EmptyOutput {
+        stderr: String,
+    },
+    InvalidUtf8,
+    /// The output is not one of the known JSON formats.
+    InvalidJson {
+        error: serde_json::Error,
+        output: String,
+    },
+}
+
+impl fmt::Display for ListError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ListError::NotInstalled => f.write_str(
+                "termux-usb not found, install the termux-api package and the Termux:API app",
+            ),
+            ListError::Spawn(e) => write!(f, "could not run termux-usb: {}", e),
+            ListError::Failed { status, stderr } => {
+                write!(f, "termux-usb -l failed ({})", status)?;
+                if !stderr.is_empty() {
+                    write!(f, ": {}", stderr.trim_end())?;
+                }
+                Ok(())
+            }
+            ListError::EmptyOutput { stderr } => {
+                f.write_str("termux-usb -l printed nothing, is the Termux:API app installed?")?;
+                if !stderr.is_empty() {
+                    write!(f, " ({})", stderr.trim_end())?;
+                }
+                Ok(())
+            }
+            ListError::InvalidUtf8 => f.write_str("termux-usb -l output is not valid UTF-8"),
+            ListError::InvalidJson { error, output } => write!(
+                f,
+                "unexpected termux-usb -l output {:?}: {}",
+                output.trim_end(),
+                error
+            ),
+        }
+    }
+}
+
+impl error::Error for ListError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            ListError::Spawn(e) => Some(e),
+            ListError::InvalidJson { error, .. } => Some(error),
+            _ => None,
+        }
+    }
+}
+
+/// Device reported by `termux-usb -l`.
+///
+/// Current Termux:API only reports the path; the other fields are filled in
+/// if a richer array-of-objects format is emitted.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ListedDevice {
+    pub path: String,
+    pub vendor_id: Option<u16>,
+    pub product_id: Option<u16>,
+    pub manufacturer: Option<String>,
+    pub product: Option<String>,
+    pub serial_number: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum ListEntry {
+    Path(String),
+    Object(ListObject),
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ListObject {
+    #[serde(alias = "path", alias = "name", alias = "deviceName")]
+    device: String,
+    #[serde(default, alias = "vendor_id", deserialize_with = "deserialize_usb_id")]
+    vendor_id: Option<u16>,
+    #[serde(default, alias = "product_id", deserialize_with = "deserialize_usb_id")]
+    product_id: Option<u16>,
+    #[serde(default, alias = "manufacturerName")]
+    manufacturer: Option<String>,
+    #[serde(default, alias = "productName")]
+    product: Option<String>,
+    #[serde(default, alias = "serial_number", alias = "serial")]
+    serial_number: Option<String>,
+}
+
+/// Accepts ids as numbers or hex strings (`"18d1"`, `"0x18d1"`).
+fn deserialize_usb_id<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<u16>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum UsbId {
+        Number(u16),
+        Hex(String),
+    }
+
+    match Option::<UsbId>::deserialize(deserializer)? {
+        None => Ok(None),
+        Some(UsbId::Number(id)) => Ok(Some(id)),
+        Some(UsbId::Hex(s)) => {
+            let hex = s.trim_start_matches("0x").trim_start_matches("0X");
+            u16::from_str_radix(hex, 16)
+                .map(Some)
+                .map_err(|_| de::Error::custom(format!("invalid USB id {:?}", s)))
+        }
+    }
+}
+
+impl From<ListEntry> for ListedDevice {
+    fn from(entry: ListEntry) -> Self {
+        match entry {
+            ListEntry::Path(path) => ListedDevice {
+                path,
+                vendor_id: None,
+                product_id: None,
+                manufacturer: None,
+                product: None,
+                serial_number: None,
+            },
+            ListEntry::Object(obj) => ListedDevice {
+                path: obj.device,
+                vendor_id: obj.vendor_id,
+                product_id: obj.product_id,
+                manufacturer: obj.manufacturer,
+                product: obj.product,
+                serial_number: obj.serial_number,
+            },
+        }
+    }
+}
+
+/// Parses the output of `termux-usb -l`, an array of device paths or of
+/// objects describing devices.
+pub fn parse_termux_usb_list(stdout: &[u8], stderr: &str) -> Result<Vec<ListedDevice>, ListError> {
+    let stdout = str::from_utf8(stdout).map_err(|_| ListError::InvalidUtf8)?;
+    if stdout.trim().is_empty() {
+        return Err(ListError::EmptyOutput {
+            stderr: stderr.to_string(),
+        });
+    }
+    let entries: Vec<ListEntry> =
+        serde_json::from_str(stdout).map_err(|error| ListError::InvalidJson {
+            error,
+            output: stdout.to_string(),
+        })?;
+    Ok(entries.into_iter().map(ListedDevice::from).collect())
+}
+
+/// Checks the exit status of `termux-usb -l` and parses its output.
+pub fn termux_usb_list_from_output(out: &Output) -> Result<Vec<ListedDevice>, ListError> {
+    let stderr = String::from_utf8_lossy(&out.stderr);
+    if !out.status.success() {
+        return Err(ListError::Failed {
+            status: out.status,
+            stderr: stderr.into_owned(),
+        });
+    }
+    if !stderr.is_empty() {
+        warn!("termux-usb -l: {}", stderr.trim_end());
+    }
+    parse_termux_usb_list(&out.stdout, &stderr)
+}
+
+/// Lists devices reported by `termux-usb -l`.
+pub fn list_termux_usb_devices() -> Result<Vec<ListedDevice>, ListError> {
+    let out = Command::new("termux-usb")
+        .arg("-l")
+        .output()
+        .map_err(|e| match e.kind() {
+            io::ErrorKind::NotFound => ListError::NotInstalled,
+            _ => ListError::Spawn(e),
+        })?;
+    termux_usb_list_from_output(&out)
+}
+
+/// Lists device paths reported by `termux-usb -l`.
+pub fn get_termux_usb_list() -> Result<Vec<String>, ListError> {
+    Ok(list_termux_usb_devices()?
+        .into_iter()
+        .map(|d| d.path)
+        .collect())
+}
+
+/// Starts `self_path` under `termux-usb -e` for device `dev`.
+///
+/// The callback gets `TERMUX_USB_DEV` and `TERMUX_ADB_SOCK_FD` in its
+/// environment, so it can hand the granted fd back over `sock_fd`
+/// (see [`handoff::sendfd_to_adb`]). Standard error is piped so that
+/// failures can be told apart, standard output is inherited.
+pub fn spawn_under_termux_usb(dev: &str, self_path: &Path, sock_fd: RawFd) -> io::Result<Child> {
+    let mut cmd = Command::new("termux-usb");
+    cmd.arg("-e");
+    cmd.arg(self_path);
+    cmd.args(["-E", "-r", dev]);
+    cmd.env(TERMUX_USB_DEV, dev);
+    cmd.env(TERMUX_ADB_SOCK_FD, sock_fd.to_string());
+    cmd.stdout(Stdio::inherit());
+    cmd.stderr(Stdio::piped());
+    cmd.spawn()
+}
+
+/// Runs `self_path` under `termux-usb -e` for device `dev` and waits for it
+/// to finish (see [`spawn_under_termux_usb`]).
+pub fn run_under_termux_usb(dev: &str, self_path: &Path, sock_fd: RawFd) -> io::Result<Output> {
+    spawn_under_termux_usb(dev, self_path, sock_fd)?.wait_with_output()
+}
+
+/// Asks `termux-usb` for access to `dev` and receives the fd on `sock_recv`.
+///
+/// `sock_send` must be the peer of `sock_recv` and is inherited by the
+/// `self_path` callback, which is expected to call
+/// [`handoff::sendfd_to_adb`]. The socket is polled while `termux-usb` runs;
+/// if it exits without the callback sending anything, its exit status and
+/// error output decide which [`HandoffError`] is returned. If nothing
+/// happens within `timeout`, `termux-usb` is killed and
+/// [`HandoffError::Timeout`] is returned.
+pub fn request_usb_fd(
+    dev: &str,
+    self_path: &Path,
+    sock_send: &UnixDatagram,
+    sock_recv: &UnixDatagram,
+    timeout: Option<Duration>,
+) -> Result<ReceivedUsbFd, HandoffError> {
+    let sock_fd = handoff::clear_cloexec_flag(sock_send);
+    let mut child = spawn_under_termux_usb(dev, self_path, sock_fd)?;
+
+    // drain stderr in the background so a chatty child never blocks on it
+    let stderr_reader = child.stderr.take().map(|mut pipe| {
+        thread::spawn(move || {
+            let mut stderr = String::new();
+            _ = pipe.read_to_string(&mut stderr);
+            stderr
+        })
+    });
+    let stderr = || {
+        let stderr = stderr_reader
+            .and_then(|reader| reader.join().ok())
+            .unwrap_or_default();
+        if !stderr.is_empty() {
+            debug!("termux-usb stderr: {}", stderr.trim_end());
+        }
+        stderr
+    };
+
+    let deadline = timeout.map(|timeout| Instant::now() + timeout);
+    loop {
+        let mut poll_timeout = POLL_INTERVAL;
+        if let Some(deadline) = deadline {
+            let now = Instant::now();
+            if now >= deadline {
+                _ = child.kill();
+                _ = child.wait();
+                return Err(HandoffError::Timeout {
+                    dev: dev.to_string(),
+                    after: timeout.unwrap_or_default(),
+                });
+            }
+            poll_timeout = poll_timeout.min(deadline - now);
+        }
+
+        let mut fds = [PollFd::new(sock_recv.as_raw_fd(), PollFlags::POLLIN)];
+        match poll(&mut fds, poll_timeout.as_millis() as c_int) {
+            Ok(0) | Err(Errno::EINTR) => {}
+            Ok(_) => {
+                let received = handoff::recv_usb_fd(sock_recv);
+                // the callback is done, termux-usb exits right after it
+                _ = child.wait();
+                stderr();
+                return received;
+            }
+            Err(e) => return Err(io::Error::from(e).into()),
+        }
+
+        if let Some(status) = child.try_wait()? {
+            // the message may have been queued just before the exit
+            sock_recv.set_nonblocking(true)?;
+            let received = handoff::recv_usb_fd(sock_recv);
+            sock_recv.set_nonblocking(false)?;
+
+            return match received {
+                Err(HandoffError::Io(e)) if e.kind() == io::ErrorKind::WouldBlock => {
+                    Err(classify_failure(dev, status, &stderr()))
+                }
+                received => received,
+            };
+        }
+    }
+}
+
+/// What termux-usb prints, on a line of its own, for a device that is not
+/// attached.
+const TERMUX_USB_NO_SUCH_DEVICE: &str = "No such device";
+
+/// Guesses why `termux-usb` ended without the callback sending anything.
+fn classify_failure(dev: &str, status: ExitStatus, stderr: &str) -> HandoffError {
+    let dev = dev.to_string();
+    if stderr.to_lowercase().contains("denied") {
+        HandoffError::PermissionDenied { dev }
+    } else if stderr
+        .lines()
+        .any(|line| line.trim() == TERMUX_USB_NO_SUCH_DEVICE)
+    {
+        // shell errors like "termux-usb: not found" are not meant here
+        HandoffError::DeviceGone { dev }
+    } else {
+        HandoffError::ChildCrashed {
+            dev,
+            status: Some(status),
+        }
+    }
+}
+
+/// Parses the fd passed by `termux-usb` in `TERMUX_USB_FD` and checks that
+/// it is the usbfs node `TERMUX_USB_DEV` names.
+///
+/// An unusable fd is reported as [`HandoffError::BadFd`].
+pub fn usb_fd_from_env() -> anyhow::Result<c_int> {
+    let fd_str = env::var(TERMUX_USB_FD).context(concat!(
+        "error: TERMUX_USB_FD not set, ",
+        "you must run termux-usb -e ./termux-usb-test -E -r /dev/bus/usb/..."
+    ))?;
+    let dev = env::var(TERMUX_USB_DEV).ok();
+    let bad_fd = |reason: String| HandoffError::BadFd {
+        dev: dev.clone().unwrap_or_else(|| TERMUX_USB_FD.to_string()),
+        reason,
+    };
+    let usb_fd = fd_str
+        .parse::<c_int>()
+        .map_err(|_| bad_fd(format!("could not parse TERMUX_USB_FD {:?}", fd_str)))?;
+    let info = usbfs::validate_usb_fd(usb_fd, dev.as_deref()).map_err(|e| bad_fd(e.to_string()))?;
+    debug!(
+        "TERMUX_USB_FD {} is bus {} device {}",
+        usb_fd, info.busnum, info.devnum
+    );
+    Ok(usb_fd)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::process::ExitStatusExt;
+
+    fn output(code: i32, stdout: &[u8], stderr: &[u8]) -> Output {
+        Output {
+            status: ExitStatus::from_raw(code << 8),
+            stdout: stdout.to_vec(),
+            stderr: stderr.to_vec(),
+        }
+    }
+
+    fn paths(devices: &[ListedDevice]) -> Vec<&str> {
+        devices.iter().map(|d| d.path.as_str()).collect()
+    }
+
+    #[test]
+    fn parses_array_of_strings() {
+        let out = output(
+            0,
+            b"[\n  \"/dev/bus/usb/001/002\",\n  \"/dev/bus/usb/002/003\"\n]\n",
+            b"",
+        );
+        let devices = termux_usb_list_from_output(&out).unwrap();
+        assert_eq!(
+            paths(&devices),
+            ["/dev/bus/usb/001/002", "/dev/bus/usb/002/003"]
+        );
+        assert_eq!(devices[0].vendor_id, None);
+    }
+
+    #[test]
+    fn parses_empty_list() {
+        let out = output(0, b"[]\n", b"");
+        assert!(termux_usb_list_from_output(&out).unwrap().is_empty());
+    }
+
+    #[test]
+    fn parses_array_of_objects() {
+        let out = output(
+            0,
+            br#"[{"device": "/dev/bus/usb/001/002", "vendorId": 6353, "productId": "0x4ee7",
+                  "manufacturerName": "Google", "productName": "Pixel", "serialNumber": "ABC"},
+                 {"path": "/dev/bus/usb/001/003", "vendor_id": "0bda"},
+                 "/dev/bus/usb/001/004"]"#,
+            b"",
+        );
+        let devices = termux_usb_list_from_output(&out).unwrap();
+        assert_eq!(
+            paths(&devices),
+            [
+                "/dev/bus/usb/001/002",
+                "/dev/bus/usb/001/003",
+                "/dev/bus/usb/001/004"
+            ]
+        );
+        assert_eq!(devices[0].vendor_id, Some(0x18d1));
+        assert_eq!(devices[0].product_id, Some(0x4ee7));
+        assert_eq!(devices[0].manufacturer.as_deref(), Some("Google"));
+        assert_eq!(devices[0].product.as_deref(), Some("Pixel"));
+        assert_eq!(devices[0].serial_number.as_deref(), Some("ABC"));
+        assert_eq!(devices[1].vendor_id, Some(0x0bda));
+        assert_eq!(devices[1].product_id, None);
+    }
+
+    #[test]
+    fn classifies_missing_device_but_not_missing_binary() {
+        let status = ExitStatus::from_raw(1 << 8);
+        let dev = "/dev/bus/usb/001/002";
+        assert!(matches!(
+            classify_failure(dev, status, "No such device\n"),
+            HandoffError::DeviceGone { .. }
+        ));
+        for stderr in [
+            "sh: 1: termux-usb: not found\n",
+            "bash: termux-usb: command not found\n",
+        ] {
+            assert!(
+                matches!(
+                    classify_failure(dev, status, stderr),
+                    HandoffError::ChildCrashed { .. }
+                ),
+                "{}",
+                stderr
+            );
+        }
+    }
+
+    #[test]
+    fn reports_failure_with_stderr() {
+        let out = output(1, b"", b"Termux:API is not installed\n");
+        match termux_usb_list_from_output(&out) {
+            Err(e @ ListError::Failed { .. }) => {
+                assert!(
+                    e.to_string().contains("Termux:API is not installed"),
+                    "{}",
+                    e
+                )
+            }
+            res => panic!("unexpected {:?}", res),
+        }
+    }
+
+    #[test]
+    fn reports_empty_output() {
+        let out = output(0, b"  \n", b"");
+        assert!(matches!(
+            termux_usb_list_from_output(&out),
+            Err(ListError::EmptyOutput { .. })
+        ));
+    }
+
+    #[test]
+    fn reports_invalid_output() {
+        let out = output(0, b"\xff\xfe", b"");
+        assert!(matches!(
+            termux_usb_list_from_output(&out),
+            Err(ListError::InvalidUtf8)
+        ));
+
+        for stdout in [
+            &b"{\"device\": \"/dev/bus/usb/001/002\"}"[..],
+            b"[42]",
+            b"[\"/dev/bus",
+        ] {
+            let out = output(0, stdout, b"");
+            assert!(
+                matches!(
+                    termux_usb_list_from_output(&out),
+                    Err(ListError::InvalidJson { .. })
+                ),
+                "{:?}",
+                String::from_utf8_lossy(stdout)
+            );
+        }
+    }
+}
@@ -0,0 +1,390 @@
+//! Streaming between byte streams and bulk endpoints, like `cat` for USB.
+//!
+//! Input is copied to the OUT endpoint and the IN endpoint to output, each
+//! in its own thread so request/response protocols work over one pipe.
+//! Input is read by a detached thread so a blocked read, e.g. of a
+//! terminal, does not keep the other side from stopping.
+
+use anyhow::Context;
+use log::debug;
+use serde::Serialize;
+use std::{
+    fmt,
+    io::{self, Read, Write},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        mpsc::{self, Receiver, RecvTimeoutError},
+    },
+    thread,
+    time::Duration,
+};
+
+use crate::handle::UsbDeviceHandle;
+
+/// Transfer size used unless configured otherwise.
+pub const DEFAULT_CHUNK_SIZE: usize = 16 * 1024;
+
+/// How often the OUT side checks whether the IN side stopped while it waits
+/// for input.
+const INPUT_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// A bulk endpoint to stream through.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BulkEndpoint {
+    pub address: u8,
+    /// `wMaxPacketSize`, used to decide when a zero-length packet is due.
+    pub max_packet_size: u16,
+}
+
+/// How to stream.
+#[derive(Debug, Clone)]
+pub struct CatOptions {
+    /// Largest transfer issued in either direction.
+    pub chunk_size: usize,
+    /// Timeout of every transfer; zero waits forever.
+    pub timeout: Duration,
+    /// Send a zero-length packet when the input ends on a multiple of
+    /// `wMaxPacketSize`, so the device sees the end of the transfer.
+    pub send_zlp: bool,
+    /// Stop reading once the device sends a zero-length packet.
+    pub stop_on_zlp: bool,
+    /// Stop reading after an IN timeout instead of retrying, once the
+    /// input is exhausted.
+    pub stop_on_idle: bool,
+}
+
+impl Default for CatOptions {
+    fn default() -> Self {
+        CatOptions {
+            chunk_size: DEFAULT_CHUNK_SIZE,
+            timeout: Duration::from_secs(1),
+            send_zlp: false,
+            stop_on_zlp: false,
+            stop_on_idle: true,
+        }
+    }
+}
+
+/// What was moved in each direction.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct CatCounters {
+    pub bytes_out: u64,
+    pub transfers_out: u64,
+    pub zlps_out: u64,
+    pub bytes_in: u64,
+    pub transfers_in: u64,
+    pub zlps_in: u64,
+    pub timeouts_in: u64,
+}
+
+/// What the two sides of [`usbcat`] share.
+#[derive(Default)]
+struct Shared {
+    counters: Counters,
+    /// Set once the OUT side is done, so IN timeouts count as idle.
+    input_done: AtomicBool,
+    /// Set when the IN side is done or either side failed.
+    stop: AtomicBool,
+}
+
+#[derive(Default)]
+struct Counters {
+    bytes_out: AtomicU64,
+    transfers_out: AtomicU64,
+    zlps_out: AtomicU64,
+    bytes_in: AtomicU64,
+    transfers_in: AtomicU64,
+    zlps_in: AtomicU64,
+    timeouts_in: AtomicU64,
+}
+
+impl Counters {
+    fn add(counter: &AtomicU64, n: u64) {
+        counter.fetch_add(n, Ordering::Relaxed);
+    }
+
+    fn snapshot(&self) -> CatCounters {
+        let get = |c: &AtomicU64| c.load(Ordering::Relaxed);
+        CatCounters {
+            bytes_out: get(&self.bytes_out),
+            transfers_out: get(&self.transfers_out),
+            zlps_out: get(&self.zlps_out),
+            bytes_in: get(&self.bytes_in),
+            transfers_in: get(&self.transfers_in),
+            zlps_in: get(&self.zlps_in),
+            timeouts_in: get(&self.timeouts_in),
+        }
+    }
+}
+
+/// Copies `input` to `out_ep` and `in_ep` to `output` until the input ends
+/// and the IN side stops (see [`CatOptions`]). Either endpoint may be left
+/// out to stream in one direction only. The interface must be claimed.
+///
+/// Once the IN side stops or either side fails, the other stops after its
+/// current transfer and the first error is returned; input still being
+/// read is abandoned.
+pub fn usbcat<H, R, W>(
+    handle: &H,
+    out_ep: Option<BulkEndpoint>,
+    in_ep: Option<BulkEndpoint>,
+    options: &CatOptions,
+    input: R,
+    output: W,
+) -> anyhow::Result<CatCounters>
+where
+    H: UsbDeviceHandle + ?Sized,
+    R: Read + Send + 'static,
+    W: Write + Send,
+{
+    let shared = Shared {
+        input_done: AtomicBool::new(out_ep.is_none()),
+        ..Shared::default()
+    };
+    let chunk_size = options.chunk_size.max(1);
+
+    thread::scope(|s| {
+        let sender = out_ep.map(|ep| {
+            let shared = &shared;
+            let chunks = read_input(input, chunk_size);
+            s.spawn(move || {
+                let result = send(handle, ep, options, chunks, shared);
+                shared.input_done.store(true, Ordering::Relaxed);
+                if result.is_err() {
+                    shared.stop.store(true, Ordering::Relaxed);
+                }
+                result
+            })
+        });
+        let received = in_ep.map_or(Ok(()), |ep| {
+            let result = receive(handle, ep, chunk_size, options, output, &shared);
+            shared.stop.store(true, Ordering::Relaxed);
+            result
+        });
+        // a failed sender stops the receiver, which then returns Ok
+        let sent = sender.map_or(Ok(()), |sender| {
+            sender.join().expect("sender thread panicked")
+        });
+        received.and(sent)
+    })?;
+    Ok(shared.counters.snapshot())
+}
+
+/// Reads `input` in chunks on a detached thread. The channel closes at the
+/// end of the input; the thread exits once the receiving end is dropped and
+/// its current read returns.
+fn read_input<R: Read + Send + 'static>(
+    mut input: R,
+    chunk_size: usize,
+) -> Receiver<io::Result<Vec<u8>>> {
+    let (tx, rx) = mpsc::sync_channel(1);
+    thread::spawn(move || {
+        let mut buf = vec![0; chunk_size];
+        loop {
+            let chunk = match input.read(&mut buf) {
+                Ok(0) => return,
+                Ok(n) => Ok(buf[..n].to_vec()),
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => Err(e),
+            };
+            let failed = chunk.is_err();
+            if tx.send(chunk).is_err() || failed {
+                return;
+            }
+        }
+    });
+    rx
+}
+
+fn send<H: UsbDeviceHandle + ?Sized>(
+    handle: &H,
+    ep: BulkEndpoint,
+    options: &CatOptions,
+    chunks: Receiver<io::Result<Vec<u8>>>,
+    shared: &Shared,
+) -> anyhow::Result<()> {
+    let (counters, stop) = (&shared.counters, &shared.stop);
+    let mut last_len = 0;
+    loop {
+        if stop.load(Ordering::Relaxed) {
+            return Ok(());
+        }
+        let buf = match chunks.recv_timeout(INPUT_POLL_INTERVAL) {
+            Ok(chunk) => chunk.context("error reading input")?,
+            Err(RecvTimeoutError::Timeout) => continue,
+            Err(RecvTimeoutError::Disconnected) => break,
+        };
+        let mut chunk = &buf[..];
+        while !chunk.is_empty() {
+            if stop.load(Ordering::Relaxed) {
+                return Ok(());
+            }
+            let written = handle
+                .write_bulk(ep.address, chunk, options.timeout)
+                .with_context(|| format!("bulk OUT transfer to {:#04x} failed", ep.address))?;
+            Counters::add(&counters.bytes_out, written as u64);
+            Counters::add(&counters.transfers_out, 1);
+            last_len = written;
+            chunk = &chunk[written..];
+        }
+    }
+    let max_packet = usize::from(ep.max_packet_size);
+    if options.send_zlp && last_len > 0 && max_packet > 0 && last_len % max_packet == 0 {
+        debug!("sending zero-length packet to {:#04x}", ep.address);
+        handle
+            .write_bulk(ep.address, &[], options.timeout)
+            .with_context(|| format!("zero-length packet to {:#04x} failed", ep.address))?;
+        Counters::add(&counters.zlps_out, 1);
+    }
+    Ok(())
+}
+
+fn receive<H: UsbDeviceHandle + ?Sized, W: Write>(
+    handle: &H,
+    ep: BulkEndpoint,
+    chunk_size: usize,
+    options: &CatOptions,
+    mut output: W,
+    shared: &Shared,
+) -> anyhow::Result<()> {
+    let Shared {
+        counters,
+        input_done,
+        stop,
+    } = shared;
+    let mut buf = vec![0; chunk_size];
+    while !stop.load(Ordering::Relaxed) {
+        // only a timeout that started after the input ended counts as idle
+        let idle_after_input = options.stop_on_idle && input_done.load(Ordering::Relaxed);
+        match handle.read_bulk(ep.address, &mut buf, options.timeout) {
+            Ok(0) => {
+                Counters::add(&counters.zlps_in, 1);
+                if options.stop_on_zlp {
+                    debug!("zero-length packet from {:#04x}, stopping", ep.address);
+                    return Ok(());
+                }
+            }
+            Ok(n) => {
+                Counters::add(&counters.bytes_in, n as u64);
+                Counters::add(&counters.transfers_in, 1);
+                match output.write_all(&buf[..n]).and_then(|()| output.flush()) {
+                    Ok(()) => {}
+                    // the reader went away, e.g. `| head`
+                    Err(e) if e.kind() == io::ErrorKind::BrokenPipe => return Ok(()),
+                    Err(e) => return Err(e).context("error writing output"),
+                }
+            }
+            Err(e) if e.kind() == io::ErrorKind::TimedOut => {
+                Counters::add(&counters.timeouts_in, 1);
+                if idle_after_input {
+                    return Ok(());
+                }
+            }
+            Err(e) => {
+                return Err(e)
+                    .with_context(|| format!("bulk IN transfer from {:#04x} failed", ep.address))
+            }
+        }
+    }
+    Ok(())
+}
+
+impl fmt::Display for CatCounters {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "out: {} bytes in {} transfers, {} zero-length",
+            self.bytes_out, self.transfers_out, self.zlps_out
+        )?;
+        writeln!(
+            f,
+            "in:  {} bytes in {} transfers, {} zero-length, {} timeouts",
+            self.bytes_in, self.transfers_in, self.zlps_in, self.timeouts_in
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::handle::testing::Loopback;
+
+    #[test]
+    fn streams_through_loopback_with_zlp() {
+        let out_ep = BulkEndpoint {
+            address: 0x01,
+            max_packet_size: 4,
+        };
+        let in_ep = BulkEndpoint {
+            address: 0x81,
+            max_packet_size: 4,
+        };
+        let options = CatOptions {
+            chunk_size: 8,
+            send_zlp: true,
+            stop_on_zlp: true,
+            ..CatOptions::default()
+        };
+        let mut output = vec![];
+        let counters = usbcat(
+            &Loopback::default(),
+            Some(out_ep),
+            Some(in_ep),
+            &options,
+            &b"0123456789ab"[..],
+            &mut output,
+        )
+        .unwrap();
+        assert_eq!(output, b"0123456789ab");
+        assert_eq!(counters.bytes_out, 12);
+        assert_eq!(counters.transfers_out, 2);
+        // 12 bytes end on a packet boundary, so a ZLP follows and ends reading
+        assert_eq!(counters.zlps_out, 1);
+        assert_eq!(counters.zlps_in, 1);
+        assert_eq!(counters.bytes_in, 12);
+
+        // without an IN endpoint, all input is still sent
+        let device = Loopback::default();
+        let counters = usbcat(
+            &device,
+            Some(out_ep),
+            None,
+            &options,
+            &b"0123"[..],
+            io::sink(),
+        )
+        .unwrap();
+        assert_eq!(counters.bytes_out, 4);
+    }
+
+    #[test]
+    fn stops_when_in_side_ends_with_input_open() {
+        let endpoint = |address| BulkEndpoint {
+            address,
+            max_packet_size: 4,
+        };
+        let options = CatOptions {
+            stop_on_zlp: true,
+            stop_on_idle: false,
+            ..CatOptions::default()
+        };
+        let device = Loopback::default();
+        device.push(b"hi");
+        device.push(&[]);
+        // the other end stays open, so reading the input never ends
+        let (input, _writer) = std::os::unix::net::UnixStream::pair().unwrap();
+        let mut output = vec![];
+        let counters = usbcat(
+            &device,
+            Some(endpoint(0x01)),
+            Some(endpoint(0x81)),
+            &options,
+            input,
+            &mut output,
+        )
+        .unwrap();
+        assert_eq!(output, b"hi");
+        assert_eq!(counters.zlps_in, 1);
+        assert_eq!(counters.bytes_out, 0);
+    }
+}
@@ -0,0 +1,507 @@
+//! Raw usbfs ioctls on a device fd, as declared in `linux/usbdevice_fs.h`.
+
+use libc::{c_char, c_int, c_uint, c_void};
+use nix::{
+    errno::Errno,
+    fcntl::{fcntl, FcntlArg, OFlag},
+    ioctl_none, ioctl_read, ioctl_readwrite,
+    sys::stat::{fstat, SFlag},
+};
+use std::{error::Error, fmt, io, os::fd::RawFd, time::Duration};
+
+use crate::{
+    device::{major, minor},
+    handle::UsbDeviceHandle,
+    sysfs::parse_dev_path,
+};
+
+/// Major number of usbfs device nodes (`/dev/bus/usb/BBB/DDD`).
+pub const USB_DEVICE_MAJOR: u64 = 189;
+
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy)]
+struct UsbdevfsConnectinfo {
+    devnum: c_uint,
+    slow: u8,
+}
+
+#[repr(C)]
+struct UsbdevfsCtrltransfer {
+    request_type: u8,
+    request: u8,
+    value: u16,
+    index: u16,
+    length: u16,
+    timeout: u32,
+    data: *mut c_void,
+}
+
+#[repr(C)]
+struct UsbdevfsBulktransfer {
+    ep: c_uint,
+    len: c_uint,
+    timeout: c_uint,
+    data: *mut c_void,
+}
+
+#[repr(C)]
+struct UsbdevfsGetdriver {
+    interface: c_uint,
+    driver: [c_char; 256],
+}
+
+#[repr(C)]
+struct UsbdevfsIoctl {
+    ifno: c_int,
+    ioctl_code: c_int,
+    data: *mut c_void,
+}
+
+ioctl_readwrite!(usbdevfs_control, b'U', 0, UsbdevfsCtrltransfer);
+ioctl_readwrite!(usbdevfs_bulk, b'U', 2, UsbdevfsBulktransfer);
+ioctl_read!(usbdevfs_claiminterface, b'U', 15, c_uint);
+ioctl_read!(usbdevfs_releaseinterface, b'U', 16, c_uint);
+ioctl_readwrite!(usbdevfs_ioctl, b'U', 18, UsbdevfsIoctl);
+ioctl_none!(usbdevfs_reset, b'U', 20);
+ioctl_read!(usbdevfs_clear_halt, b'U', 21, c_uint);
+ioctl_none!(usbdevfs_get_speed, b'U', 31);
+
+/// Sub-requests for `USBDEVFS_IOCTL`, passed as values rather than issued.
+const USBDEVFS_DISCONNECT: c_int = nix::request_code_none!(b'U', 22) as c_int;
+const USBDEVFS_CONNECT: c_int = nix::request_code_none!(b'U', 23) as c_int;
+
+/// `USBDEVFS_CONNECTINFO` and `USBDEVFS_GETDRIVER` are declared `_IOW`
+/// although the kernel fills in the struct, so nix's typed wrappers don't fit.
+fn connectinfo_request() -> libc::c_ulong {
+    nix::request_code_write!(b'U', 17, std::mem::size_of::<UsbdevfsConnectinfo>()) as _
+}
+
+fn getdriver_request() -> libc::c_ulong {
+    nix::request_code_write!(b'U', 8, std::mem::size_of::<UsbdevfsGetdriver>()) as _
+}
+
+/// Device address and whether it is a low speed device
+/// (`USBDEVFS_CONNECTINFO`).
+pub fn connect_info(fd: RawFd) -> io::Result<(u32, bool)> {
+    let mut info = UsbdevfsConnectinfo::default();
+    let res = unsafe { libc::ioctl(fd, connectinfo_request() as _, &mut info) };
+    Errno::result(res)?;
+    Ok((info.devnum, info.slow != 0))
+}
+
+/// Link speed as a name like `high` (`USBDEVFS_GET_SPEED`).
+pub fn speed(fd: RawFd) -> io::Result<&'static str> {
+    let speed: c_int = unsafe { usbdevfs_get_speed(fd) }?;
+    // enum usb_device_speed
+    Ok(match speed {
+        1 => "low",
+        2 => "full",
+        3 => "high",
+        4 => "wireless",
+        5 => "super",
+        6 => "super+",
+        _ => "unknown",
+    })
+}
+
+/// A device handle that issues usbfs ioctls on the fd directly.
+///
+/// The fd is borrowed: it stays open when the handle is dropped, as it does
+/// with libusb's `wrap_sys_device`.
+#[derive(Debug)]
+pub struct UsbfsHandle {
+    fd: RawFd,
+}
+
+impl UsbfsHandle {
+    pub fn new(fd: RawFd) -> Self {
+        UsbfsHandle { fd }
+    }
+
+    pub fn fd(&self) -> RawFd {
+        self.fd
+    }
+
+    fn control(&self, mut xfer: UsbdevfsCtrltransfer) -> io::Result<usize> {
+        let n = unsafe { usbdevfs_control(self.fd, &mut xfer) }?;
+        Ok(n as usize)
+    }
+
+    /// Bulk and interrupt transfers both go through `USBDEVFS_BULK`; the
+    /// kernel picks the pipe type from the endpoint descriptor.
+    fn bulk(
+        &self,
+        endpoint: u8,
+        data: *mut c_void,
+        length: usize,
+        timeout: Duration,
+    ) -> io::Result<usize> {
+        let mut xfer = UsbdevfsBulktransfer {
+            ep: endpoint.into(),
+            len: c_uint::try_from(length).map_err(|_| invalid_length(length))?,
+            timeout: timeout_ms(timeout),
+            data,
+        };
+        let n = unsafe { usbdevfs_bulk(self.fd, &mut xfer) }?;
+        Ok(n as usize)
+    }
+
+    fn interface_ioctl(&self, interface: u8, ioctl_code: c_int) -> io::Result<()> {
+        let mut cmd = UsbdevfsIoctl {
+            ifno: interface.into(),
+            ioctl_code,
+            data: std::ptr::null_mut(),
+        };
+        unsafe { usbdevfs_ioctl(self.fd, &mut cmd) }?;
+        Ok(())
+    }
+}
+
+impl UsbdevfsCtrltransfer {
+    fn new(
+        request_type: u8,
+        request: u8,
+        value: u16,
+        index: u16,
+        data: *mut c_void,
+        length: usize,
+        timeout: Duration,
+    ) -> io::Result<Self> {
+        Ok(UsbdevfsCtrltransfer {
+            request_type,
+            request,
+            value,
+            index,
+            length: u16::try_from(length).map_err(|_| invalid_length(length))?,
+            timeout: timeout_ms(timeout),
+            data,
+        })
+    }
+}
+
+/// Zero means no timeout to usbfs, as it does to libusb.
+fn timeout_ms(timeout: Duration) -> u32 {
+    u32::try_from(timeout.as_millis()).unwrap_or(u32::MAX)
+}
+
+fn invalid_length(length: usize) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidInput,
+        format!("transfer of {} bytes is too long", length),
+    )
+}
+
+impl UsbDeviceHandle for UsbfsHandle {
+    fn read_control(
+        &self,
+        request_type: u8,
+        request: u8,
+        value: u16,
+        index: u16,
+        buf: &mut [u8],
+        timeout: Duration,
+    ) -> io::Result<usize> {
+        let (data, len) = (buf.as_mut_ptr().cast(), buf.len());
+        self.control(UsbdevfsCtrltransfer::new(
+            request_type,
+            request,
+            value,
+            index,
+            data,
+            len,
+            timeout,
+        )?)
+    }
+
+    fn write_control(
+        &self,
+        request_type: u8,
+        request: u8,
+        value: u16,
+        index: u16,
+        buf: &[u8],
+        timeout: Duration,
+    ) -> io::Result<usize> {
+        // usbfs only reads from the buffer for OUT transfers.
+        let (data, len) = (buf.as_ptr() as *mut c_void, buf.len());
+        self.control(UsbdevfsCtrltransfer::new(
+            request_type,
+            request,
+            value,
+            index,
+            data,
+            len,
+            timeout,
+        )?)
+    }
+
+    fn read_bulk(&self, endpoint: u8, buf: &mut [u8], timeout: Duration) -> io::Result<usize> {
+        self.bulk(endpoint, buf.as_mut_ptr().cast(), buf.len(), timeout)
+    }
+
+    fn write_bulk(&self, endpoint: u8, buf: &[u8], timeout: Duration) -> io::Result<usize> {
+        self.bulk(endpoint, buf.as_ptr() as *mut c_void, buf.len(), timeout)
+    }
+
+    fn read_interrupt(&self, endpoint: u8, buf: &mut [u8], timeout: Duration) -> io::Result<usize> {
+        self.read_bulk(endpoint, buf, timeout)
+    }
+
+    fn write_interrupt(&self, endpoint: u8, buf: &[u8], timeout: Duration) -> io::Result<usize> {
+        self.write_bulk(endpoint, buf, timeout)
+    }
+
+    fn claim_interface(&mut self, interface: u8) -> io::Result<()> {
+        let mut ifno = c_uint::from(interface);
+        unsafe { usbdevfs_claiminterface(self.fd, &mut ifno) }?;
+        Ok(())
+    }
+
+    fn release_interface(&mut self, interface: u8) -> io::Result<()> {
+        let mut ifno = c_uint::from(interface);
+        unsafe { usbdevfs_releaseinterface(self.fd, &mut ifno) }?;
+        Ok(())
+    }
+
+    fn kernel_driver_active(&self, interface: u8) -> io::Result<bool> {
+        let mut getdriver = UsbdevfsGetdriver {
+            interface: interface.into(),
+            driver: [0; 256],
+        };
+        let res = unsafe { libc::ioctl(self.fd, getdriver_request() as _, &mut getdriver) };
+        match Errno::result(res) {
+            Ok(_) => Ok(true),
+            Err(Errno::ENODATA) => Ok(false),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn detach_kernel_driver(&mut self, interface: u8) -> io::Result<()> {
+        self.interface_ioctl(interface, USBDEVFS_DISCONNECT)
+    }
+
+    fn attach_kernel_driver(&mut self, interface: u8) -> io::Result<()> {
+        self.interface_ioctl(interface, USBDEVFS_CONNECT)
+    }
+
+    fn clear_halt(&mut self, endpoint: u8) -> io::Result<()> {
+        let mut ep = c_uint::from(endpoint);
+        unsafe { usbdevfs_clear_halt(self.fd, &mut ep) }?;
+        Ok(())
+    }
+
+    fn reset(&mut self) -> io::Result<()> {
+        unsafe { usbdevfs_reset(self.fd) }?;
+        Ok(())
+    }
+}
+
+/// Why an fd is not a usable usbfs device node.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum UsbFdError {
+    /// Nothing is open under this number.
+    Closed {
+        fd: RawFd,
+    },
+    Stat {
+        fd: RawFd,
+        errno: Errno,
+    },
+    /// A regular file, pipe, socket etc.
+    NotCharDevice {
+        fd: RawFd,
+        file_type: &'static str,
+    },
+    /// A character device other than usbfs.
+    WrongMajor {
+        fd: RawFd,
+        major: u64,
+        minor: u64,
+    },
+    /// A usbfs minor number too large to encode a bus number.
+    BadMinor {
+        fd: RawFd,
+        minor: u64,
+    },
+    /// A different device than `TERMUX_USB_DEV` names.
+    DevMismatch {
+        fd: RawFd,
+        dev: String,
+        busnum: u8,
+        devnum: u8,
+    },
+    /// Opened read-only or write-only, so transfers would fail.
+    NotReadWrite {
+        fd: RawFd,
+        access: &'static str,
+    },
+}
+
+/// Bus and device number of a validated usbfs fd.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UsbFdInfo {
+    pub busnum: u8,
+    pub devnum: u8,
+}
+
+/// Bus and device number encoded in a usbfs minor number, or `None` if the
+/// bus number does not fit in a byte.
+pub fn bus_dev_from_minor(minor: u64) -> Option<(u8, u8)> {
+    let busnum = u8::try_from(minor / 128 + 1).ok()?;
+    Some((busnum, (minor % 128 + 1) as u8))
+}
+
+/// Checks that `fd` is an open usbfs node, opened read-write, and that it is
+/// the device `dev` (e.g. `TERMUX_USB_DEV`) names, if given.
+pub fn validate_usb_fd(fd: RawFd, dev: Option<&str>) -> Result<UsbFdInfo, UsbFdError> {
+    let flags = match fcntl(fd, FcntlArg::F_GETFL) {
+        Ok(flags) => OFlag::from_bits_truncate(flags),
+        Err(Errno::EBADF) => return Err(UsbFdError::Closed { fd }),
+        Err(errno) => return Err(UsbFdError::Stat { fd, errno }),
+    };
+    let st = fstat(fd).map_err(|errno| UsbFdError::Stat { fd, errno })?;
+
+    let file_type = SFlag::from_bits_truncate(st.st_mode) & SFlag::S_IFMT;
+    if file_type != SFlag::S_IFCHR {
+        let file_type = match file_type {
+            SFlag::S_IFREG => "regular file",
+            SFlag::S_IFDIR => "directory",
+            SFlag::S_IFIFO => "pipe",
+            SFlag::S_IFSOCK => "socket",
+            SFlag::S_IFBLK => "block device",
+            SFlag::S_IFLNK => "symlink",
+            _ => "unknown file type",
+        };
+        return Err(UsbFdError::NotCharDevice { fd, file_type });
+    }
+
+    let (major, minor) = (major(st.st_rdev), minor(st.st_rdev));
+    if major != USB_DEVICE_MAJOR {
+        return Err(UsbFdError::WrongMajor { fd, major, minor });
+    }
+    let (busnum, devnum) = bus_dev_from_minor(minor).ok_or(UsbFdError::BadMinor { fd, minor })?;
+    if let Some(dev) = dev {
+        if parse_dev_path(dev).is_some_and(|expected| expected != (busnum, devnum)) {
+            return Err(UsbFdError::DevMismatch {
+                fd,
+                dev: dev.to_string(),
+                busnum,
+                devnum,
+            });
+        }
+    }
+
+    let access = flags & OFlag::O_ACCMODE;
+    if access != OFlag::O_RDWR {
+        let access = if access == OFlag::O_WRONLY {
+            "write-only"
+        } else {
+            "read-only"
+        };
+        return Err(UsbFdError::NotReadWrite { fd, access });
+    }
+    Ok(UsbFdInfo { busnum, devnum })
+}
+
+impl fmt::Display for UsbFdError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            UsbFdError::Closed { fd } => write!(
+                f,
+                "fd {} is not open; was it inherited from termux-usb -e?",
+                fd
+            ),
+            UsbFdError::Stat { fd, errno } => write!(f, "could not stat fd {}: {}", fd, errno),
+            UsbFdError::NotCharDevice { fd, file_type } => write!(
+                f,
+                "fd {} is a {}, not a usbfs character device",
+                fd, file_type
+            ),
+            UsbFdError::WrongMajor { fd, major, minor } => write!(
+                f,
+                "fd {} is character device {}:{}, not a USB device (major {})",
+                fd, major, minor, USB_DEVICE_MAJOR
+            ),
+            UsbFdError::BadMinor { fd, minor } => write!(
+                f,
+                "fd {} has usbfs minor {}, which names no valid bus",
+                fd, minor
+            ),
+            UsbFdError::DevMismatch {
+                fd,
+                dev,
+                busnum,
+                devnum,
+            } => write!(
+                f,
+                "fd {} is bus {} device {}, not {}",
+                fd, busnum, devnum, dev
+            ),
+            UsbFdError::NotReadWrite { fd, access } => {
+                write!(
+                    f,
+                    "fd {} is opened {}, transfers need read-write",
+                    fd, access
+                )
+            }
+        }
+    }
+}
+
+impl Error for UsbFdError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{fs::File, os::fd::AsRawFd};
+
+    #[test]
+    fn decodes_minor() {
+        assert_eq!(bus_dev_from_minor(0), Some((1, 1)));
+        assert_eq!(bus_dev_from_minor(132), Some((2, 5)));
+        assert_eq!(bus_dev_from_minor(254 * 128 + 127), Some((255, 128)));
+        assert_eq!(bus_dev_from_minor(255 * 128), None);
+    }
+
+    #[test]
+    #[cfg(target_pointer_width = "64")]
+    fn encodes_ioctls_like_the_kernel_header() {
+        use std::mem::size_of;
+        let control = nix::request_code_readwrite!(b'U', 0, size_of::<UsbdevfsCtrltransfer>());
+        let bulk = nix::request_code_readwrite!(b'U', 2, size_of::<UsbdevfsBulktransfer>());
+        assert_eq!(control as u32, 0xc018_5500);
+        assert_eq!(bulk as u32, 0xc018_5502);
+        assert_eq!(getdriver_request() as u32, 0x4104_5508);
+        assert_eq!(USBDEVFS_DISCONNECT, 0x5516);
+    }
+
+    #[test]
+    fn usbfs_handle_fails_on_other_files() {
+        let null = File::open("/dev/null").unwrap();
+        let mut handle = UsbfsHandle::new(null.as_raw_fd());
+        let err = handle.claim_interface(0).unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::ENOTTY));
+    }
+
+    #[test]
+    fn rejects_non_usb_fds() {
+        let file = File::open("/proc/self/status").unwrap();
+        assert!(matches!(
+            validate_usb_fd(file.as_raw_fd(), None),
+            Err(UsbFdError::NotCharDevice {
+                file_type: "regular file",
+                ..
+            })
+        ));
+
+        let null = File::open("/dev/null").unwrap();
+        assert!(matches!(
+            validate_usb_fd(null.as_raw_fd(), None),
+            Err(UsbFdError::WrongMajor { major: 1, .. })
+        ));
+
+        let fd = null.as_raw_fd();
+        drop(null);
+        assert_eq!(validate_usb_fd(fd, None), Err(UsbFdError::Closed { fd }));
+    }
+}
@@ -0,0 +1,88 @@
+use std::{
+    fs::{self, File},
+    io::{Read, Seek, SeekFrom},
+    os::{fd::FromRawFd, unix::net::UnixDatagram},
+    path::PathBuf,
+    process, thread,
+};
+use termux_usb::{
+    broker::{bind_broker_socket, request_from_broker, Broker},
+    sysfs::Sysfs,
+    FakeBackend, HandoffError, UsbPermissionBackend,
+};
+
+/// Regular file standing in for a device node.
+fn fake_device(name: &str, contents: &[u8]) -> PathBuf {
+    let path = std::env::temp_dir().join(format!("termux-usb-test-{}-{}", process::id(), name));
+    fs::write(&path, contents).unwrap();
+    path
+}
+
+fn read_all(mut file: File) -> Vec<u8> {
+    let mut buf = vec![];
+    file.seek(SeekFrom::Start(0)).unwrap();
+    file.read_to_end(&mut buf).unwrap();
+    buf
+}
+
+#[test]
+fn fake_backend_hands_over_fd() {
+    let dev = fake_device("handoff", b"descriptors");
+    let backend = FakeBackend::new(&[&dev]);
+    let (sock_send, sock_recv) = UnixDatagram::pair().unwrap();
+
+    let devices = backend.list_devices().unwrap();
+    assert_eq!(devices, vec![dev.display().to_string()]);
+
+    let received = backend
+        .request_fd(&devices[0], &sock_send, &sock_recv)
+        .unwrap();
+    assert_eq!(received.dev_path, dev);
+    let fd = received.fd.expect("fd was not handed over");
+    assert_eq!(read_all(unsafe { File::from_raw_fd(fd) }), b"descriptors");
+
+    fs::remove_file(dev).unwrap();
+}
+
+#[test]
+fn fake_backend_reports_missing_device() {
+    let backend = FakeBackend::default();
+    let (sock_send, sock_recv) = UnixDatagram::pair().unwrap();
+
+    let err = backend
+        .request_fd("/nonexistent/bus/usb/001/002", &sock_send, &sock_recv)
+        .unwrap_err();
+    assert!(matches!(err, HandoffError::DeviceGone { .. }), "{:?}", err);
+    assert_eq!(err.exit_code(), 4);
+}
+
+#[test]
+fn broker_serves_cached_fd() {
+    let dev = fake_device("broker", b"broker device");
+    let socket = format!("@termux-usb-test-{}", process::id());
+    let listener = bind_broker_socket(&socket).unwrap();
+
+    let backend = FakeBackend::new(&[&dev]);
+    thread::spawn(move || {
+        let mut broker = Broker::new(Box::new(backend), Sysfs::default());
+        broker.serve(&listener)
+    });
+
+    let path = dev.display().to_string();
+    for _ in 0..2 {
+        let (dev_path, fd) = request_from_broker(&socket, &path).unwrap();
+        assert_eq!(dev_path, dev);
+        assert_eq!(read_all(File::from(fd)), b"broker device");
+    }
+
+    for selector in ["NO-SUCH-SERIAL", "/dev/bus/usb/099/099"] {
+        let err = request_from_broker(&socket, selector).unwrap_err();
+        assert!(
+            format!("{:#}", err).contains("no device matching"),
+            "{:#}",
+            err
+        );
+    }
+
+    fs::remove_file(dev).unwrap();
+}